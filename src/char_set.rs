@@ -1,58 +1,78 @@
 use std::{collections::HashMap, error::Error, fmt, ops::Index, str::FromStr};
 
+use caseless::default_case_fold_str;
+use clap::ValueEnum;
 use itertools::Itertools;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+use unicode_segmentation::UnicodeSegmentation;
 
-use crate::cli::{Casing, CharSetSelection};
+use crate::{
+    cli::{Casing, CharSetSelection},
+    util::is_safe_name_part,
+};
 
-static LETTERS_L: [char; 26] = [
-    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w',
-    'x', 'y', 'z',
+static LETTERS_L: [&str; 26] = [
+    "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r", "s", "t", "u", "v", "w",
+    "x", "y", "z",
 ];
-static LETTERS_U: [char; 26] = [
-    'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W',
-    'X', 'Y', 'Z',
+static LETTERS_U: [&str; 26] = [
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W",
+    "X", "Y", "Z",
 ];
-static LETTERS_M: [char; 52] = [
-    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w',
-    'x', 'y', 'z', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S', 'T',
-    'U', 'V', 'W', 'X', 'Y', 'Z',
+static LETTERS_M: [&str; 52] = [
+    "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r", "s", "t", "u", "v", "w",
+    "x", "y", "z", "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T",
+    "U", "V", "W", "X", "Y", "Z",
 ];
-static NUMBERS: [char; 10] = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
-static ALPHA_NUMERIC_L: [char; 36] = [
-    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w',
-    'x', 'y', 'z', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
+static NUMBERS: [&str; 10] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+static ALPHA_NUMERIC_L: [&str; 36] = [
+    "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r", "s", "t", "u", "v", "w",
+    "x", "y", "z", "0", "1", "2", "3", "4", "5", "6", "7", "8", "9",
 ];
-static ALPHA_NUMERIC_U: [char; 36] = [
-    'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W',
-    'X', 'Y', 'Z', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
+static ALPHA_NUMERIC_U: [&str; 36] = [
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W",
+    "X", "Y", "Z", "0", "1", "2", "3", "4", "5", "6", "7", "8", "9",
 ];
-static ALPHA_NUMERIC_M: [char; 62] = [
-    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w',
-    'x', 'y', 'z', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S', 'T',
-    'U', 'V', 'W', 'X', 'Y', 'Z', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
+static ALPHA_NUMERIC_M: [&str; 62] = [
+    "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r", "s", "t", "u", "v", "w",
+    "x", "y", "z", "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T",
+    "U", "V", "W", "X", "Y", "Z", "0", "1", "2", "3", "4", "5", "6", "7", "8", "9",
 ];
-static BASE_16_L: [char; 16] = [
-    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f',
+static BASE_16_L: [&str; 16] = [
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "a", "b", "c", "d", "e", "f",
 ];
-static BASE_16_U: [char; 16] = [
-    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'A', 'B', 'C', 'D', 'E', 'F',
+static BASE_16_U: [&str; 16] = [
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "A", "B", "C", "D", "E", "F",
 ];
-static BASE_64: [char; 64] = [
-    'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W',
-    'X', 'Y', 'Z', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't',
-    'u', 'v', 'w', 'x', 'y', 'z', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '-', '_',
+static BASE_32_CROCKFORD_L: [&str; 32] = [
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "a", "b", "c", "d", "e", "f", "g", "h", "j", "k", "m", "n", "p",
+    "q", "r", "s", "t", "v", "w", "x", "y", "z",
 ];
+static BASE_32_CROCKFORD_U: [&str; 32] = [
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "A", "B", "C", "D", "E", "F", "G", "H", "J", "K", "M", "N", "P",
+    "Q", "R", "S", "T", "V", "W", "X", "Y", "Z",
+];
+static BASE_64: [&str; 64] = [
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W",
+    "X", "Y", "Z", "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r", "s", "t",
+    "u", "v", "w", "x", "y", "z", "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "-", "_",
+];
+/// Characters that are easily mistaken for one another when read off a screen.
+static AMBIGUOUS: [&str; 8] = ["0", "O", "o", "1", "I", "l", "5", "S"];
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ParseCustomCharSetError {
-    IllegalChars(Vec<char>),
-    DuplicateChars(Vec<char>),
+    IllegalChars(Vec<String>),
+    LoneCombiningMarks(Vec<String>),
+    DuplicateChars(Vec<String>),
+    /// Groups of characters that are distinct, but equivalent after Unicode normalisation or case folding.
+    EquivalentChars(Vec<Vec<String>>),
 }
 impl Error for ParseCustomCharSetError {}
 impl fmt::Display for ParseCustomCharSetError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fn chars_to_string(chars: &[char]) -> String {
-            chars.iter().map(|c| format!("\'{c}\'")).join(", ")
+        fn chars_to_string(chars: &[String]) -> String {
+            chars.iter().map(|c| format!("\'{}\'", c.escape_debug())).join(", ")
         }
 
         let repr = match self {
@@ -60,67 +80,292 @@ impl fmt::Display for ParseCustomCharSetError {
                 let chars = chars_to_string(chars);
                 format!("the custom character set contains illegal characters: {chars}")
             }
+            Self::LoneCombiningMarks(chars) => {
+                let chars = chars_to_string(chars);
+                format!("the custom character set contains combining marks without a base character: {chars}")
+            }
             Self::DuplicateChars(chars) => {
                 let chars = chars_to_string(chars);
                 format!("the custom character set contains duplicate characters: {chars}")
             }
+            Self::EquivalentChars(groups) => {
+                let groups = groups
+                    .iter()
+                    .map(|group| format!("[{}]", chars_to_string(group)))
+                    .join(", ");
+                format!("the custom character set contains characters that are equivalent after normalisation or case folding: {groups}")
+            }
         };
         write!(f, "{repr}")
     }
 }
 
+/// A character set specified by the user.
+///
+/// Each "character" is a grapheme cluster, so that symbols made of several code points
+/// (e.g. `é` in NFD form, or most emoji) are never split apart.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CustomCharSet {
-    chars: Vec<char>,
+    chars: Vec<String>,
 }
 impl FromStr for CustomCharSet {
     type Err = ParseCustomCharSetError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        use sanitize_filename as sf;
+        Self::try_from_chars(s.graphemes(true).map(str::to_owned).collect())
+    }
+}
+impl fmt::Display for CustomCharSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chars = self.chars.concat();
+        write!(f, "{chars}")
+    }
+}
+impl CustomCharSet {
+    /// Validate a list of grapheme clusters as a character set.
+    fn try_from_chars(chars: Vec<String>) -> Result<Self, ParseCustomCharSetError> {
         use ParseCustomCharSetError as E;
 
-        let illegal_chars: Vec<_> = s
-            .chars()
-            .filter(|c| {
-                let c = c.to_string();
-                c != sf::sanitize_with_options(
-                    &c,
-                    sf::Options {
-                        windows: false, // this avoids filtering trailing dot
-                        ..Default::default()
-                    },
-                )
-            })
-            .collect();
+        let illegal_chars = chars.iter().filter(|c| !is_safe_name_part(c)).cloned().collect_vec();
         if !illegal_chars.is_empty() {
             Err(E::IllegalChars(illegal_chars))?;
         }
 
-        let duplicate_chars: Vec<_> = s
-            .chars()
-            .fold(HashMap::<char, usize>::new(), |mut map, c| {
+        // a lone combining mark would merge into whatever character precedes it in a name
+        let lone_marks = chars
+            .iter()
+            .filter(|c| c.chars().next().is_some_and(is_combining_mark))
+            .cloned()
+            .collect_vec();
+        if !lone_marks.is_empty() {
+            Err(E::LoneCombiningMarks(lone_marks))?;
+        }
+
+        let duplicate_chars = chars
+            .iter()
+            .fold(HashMap::<&str, usize>::new(), |mut map, c| {
                 *map.entry(c).or_default() += 1;
                 map
             })
             .into_iter()
-            .filter_map(|(c, count)| (count > 1).then_some(c))
-            .collect();
+            .filter(|&(_, count)| count > 1)
+            .map(|(c, _)| c.to_owned())
+            .sorted()
+            .collect_vec();
         if !duplicate_chars.is_empty() {
             Err(E::DuplicateChars(duplicate_chars))?;
         }
 
-        let chars = s.chars().collect();
+        // Characters that differ only in case (e.g. `a` and `A`) are distinct on case-sensitive filesystems,
+        // but anything else that folds together (e.g. NFC vs NFD, or `K` and the Kelvin sign) is not.
+        let equivalent_chars = chars
+            .iter()
+            .into_group_map_by(|c| {
+                default_case_fold_str(&c.nfc().collect::<String>())
+                    .nfc()
+                    .collect::<String>()
+            })
+            .into_values()
+            .filter(|group| match group[..] {
+                [_] => false,
+                [a, b] => !is_case_pair(a, b),
+                _ => true,
+            })
+            .map(|group| group.into_iter().cloned().collect_vec())
+            .sorted()
+            .collect_vec();
+        if !equivalent_chars.is_empty() {
+            Err(E::EquivalentChars(equivalent_chars))?;
+        }
+
         Ok(Self { chars })
     }
 }
-impl fmt::Display for CustomCharSet {
+
+/// Check if two characters are the upper and lower case forms of each other.
+fn is_case_pair(a: &str, b: &str) -> bool {
+    let pair = |upper: &str, lower: &str| upper.to_lowercase() == lower && lower.to_uppercase() == upper;
+    pair(a, b) || pair(b, a)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseCharSetExprError {
+    Empty,
+    UnknownTerm(String),
+    UnclosedBracket(String),
+    InvalidRange(String, String),
+    ExpectedOperator(String),
+    /// A case spelt out for `custom`, e.g. `custom-lower`.
+    CasedCustom(String),
+    InvalidChars(ParseCustomCharSetError),
+}
+impl Error for ParseCharSetExprError {}
+impl fmt::Display for ParseCharSetExprError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let chars = self.chars.iter().collect::<String>();
-        write!(f, "{chars}")
+        let repr = match self {
+            Self::Empty => "the character set expression is empty".into(),
+            Self::UnknownTerm(rest) => format!("unknown character set at \"{rest}\""),
+            Self::UnclosedBracket(rest) => format!("unclosed bracket expression at \"{rest}\""),
+            Self::InvalidRange(start, end) => format!("the range \'{start}\'-\'{end}\' is invalid"),
+            Self::ExpectedOperator(rest) => format!("expected `+` or `-` at \"{rest}\""),
+            Self::CasedCustom(name) => {
+                format!("the case of \"{name}\" cannot be set, since its characters come from `--custom-chars`")
+            }
+            Self::InvalidChars(err) => err.to_string(),
+        };
+        write!(f, "{repr}")
+    }
+}
+impl From<ParseCustomCharSetError> for ParseCharSetExprError {
+    fn from(err: ParseCustomCharSetError) -> Self {
+        Self::InvalidChars(err)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CharSetOp {
+    Add,
+    Remove,
+}
+
+/// A single operand of a character set expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CharSetTerm {
+    /// A predefined set whose case is set by `--case`, e.g. `letters`.
+    Selection(CharSetSelection),
+    /// A predefined set with its case spelt out, e.g. `letters-upper`.
+    Cased(CharSetSelection, Casing),
+    /// A regex-style bracket expression, e.g. `[a-f0-9]`.
+    Bracket(CustomCharSet),
+}
+
+/// A character set built by adding and removing other character sets,
+/// e.g. `letters-lower+numbers-[0o1l]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharSetExpr {
+    source: String,
+    terms: Vec<(CharSetOp, CharSetTerm)>,
+}
+impl FromStr for CharSetExpr {
+    type Err = ParseCharSetExprError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ParseCharSetExprError as E;
+
+        if s.is_empty() {
+            Err(E::Empty)?;
+        }
+
+        let mut terms = vec![];
+        let mut rest = s;
+        let mut op = CharSetOp::Add;
+        loop {
+            let (term, remaining) = parse_term(rest)?;
+            terms.push((op, term));
+            let mut chars = remaining.chars();
+            op = match chars.next() {
+                None => break,
+                Some('+') => CharSetOp::Add,
+                Some('-') => CharSetOp::Remove,
+                Some(_) => Err(E::ExpectedOperator(remaining.into()))?,
+            };
+            rest = chars.as_str();
+        }
+
+        Ok(Self {
+            source: s.into(),
+            terms,
+        })
+    }
+}
+impl fmt::Display for CharSetExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
     }
 }
 
+/// Parse a single term from the start of an expression,
+/// returning it along with the unparsed remainder.
+fn parse_term(s: &str) -> Result<(CharSetTerm, &str), ParseCharSetExprError> {
+    use ParseCharSetExprError as E;
+
+    if let Some(body) = s.strip_prefix('[') {
+        return parse_bracket(body);
+    }
+
+    // names contain hyphens too, so take the longest name that ends at an operator
+    fn name_of(value: impl ValueEnum) -> String {
+        value
+            .to_possible_value()
+            .map(|v| v.get_name().to_owned())
+            .unwrap_or_default()
+    }
+    let candidates = CharSetSelection::value_variants().iter().flat_map(|&selection| {
+        let name = name_of(selection);
+        let cased = Casing::value_variants()
+            .iter()
+            .map(|&case| (format!("{name}-{}", name_of(case)), CharSetTerm::Cased(selection, case)))
+            .collect_vec();
+        cased.into_iter().chain([(name, CharSetTerm::Selection(selection))])
+    });
+    let (name, term) = candidates
+        .filter(|(name, _)| {
+            s.strip_prefix(name.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['+', '-']))
+        })
+        .max_by_key(|(name, _)| name.len())
+        .ok_or_else(|| E::UnknownTerm(s.into()))?;
+    if let CharSetTerm::Cased(CharSetSelection::Custom, _) = term {
+        Err(E::CasedCustom(name.clone()))?;
+    }
+    Ok((term, &s[name.len()..]))
+}
+
+/// Parse the body of a bracket expression, i.e. everything after the opening `[`.
+///
+/// Ranges like `a-z` are supported between single code points. A `-` at either end is taken literally,
+/// and `\` escapes the next character.
+fn parse_bracket(s: &str) -> Result<(CharSetTerm, &str), ParseCharSetExprError> {
+    use ParseCharSetExprError as E;
+
+    let mut literals = vec![];
+    let mut graphemes = s.grapheme_indices(true);
+    let end = loop {
+        match graphemes.next() {
+            None => Err(E::UnclosedBracket(format!("[{s}")))?,
+            Some((idx, "]")) => break idx,
+            Some((_, "\\")) => match graphemes.next() {
+                None => Err(E::UnclosedBracket(format!("[{s}")))?,
+                Some((_, c)) => literals.push((c, true)),
+            },
+            Some((_, c)) => literals.push((c, false)),
+        }
+    };
+
+    let mut members = vec![];
+    let mut idx = 0;
+    while idx < literals.len() {
+        match literals[idx..] {
+            [(start, _), ("-", false), (end, _), ..] => {
+                let range = match (start.chars().exactly_one(), end.chars().exactly_one()) {
+                    (Ok(start), Ok(end)) if start <= end => start..=end,
+                    _ => Err(E::InvalidRange(start.into(), end.into()))?,
+                };
+                members.extend(range.map(String::from));
+                idx += 3;
+            }
+            [(c, _), ..] => {
+                members.push(c.to_owned());
+                idx += 1;
+            }
+            [] => unreachable!(),
+        }
+    }
+
+    let set = CustomCharSet::try_from_chars(members.into_iter().unique().collect())?;
+    Ok((CharSetTerm::Bracket(set), &s[end + 1..]))
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum CharSet {
     Custom(CustomCharSet),
@@ -133,7 +378,19 @@ pub enum CharSet {
     AlphaNumericMixed,
     Base16Lower,
     Base16Upper,
+    Base32CrockfordLower,
+    Base32CrockfordUpper,
     Base64,
+    /// The result of a character set expression.
+    Expression {
+        expr: CharSetExpr,
+        chars: Vec<String>,
+    },
+    /// Another character set with all ambiguous characters removed.
+    Unambiguous {
+        base: Box<CharSet>,
+        chars: Vec<String>,
+    },
 }
 impl TryFrom<(CharSetSelection, Option<CustomCharSet>, Option<Casing>)> for CharSet {
     type Error = String;
@@ -145,7 +402,7 @@ impl TryFrom<(CharSetSelection, Option<CustomCharSet>, Option<Casing>)> for Char
             // when `--char-set=custom`, `--custom-chars` is guaranteed to be set
             (S::Custom, None, _) => unreachable!("`--custom-chars` should be required by clap"),
             // when `--custom-chars` is set, `--char-set=custom` must be true
-            (S::Letters | S::Numbers | S::AlphaNumeric | S::Base16 | S::Base64, Some(_), _) => {
+            (S::Letters | S::Numbers | S::AlphaNumeric | S::Base16 | S::Base32 | S::Base64, Some(_), _) => {
                 Err("`--custom-chars` cannot be used unless `--char-set=custom`".to_string())
             }
             // valid combinations
@@ -159,6 +416,8 @@ impl TryFrom<(CharSetSelection, Option<CustomCharSet>, Option<Casing>)> for Char
             (S::AlphaNumeric, None, Some(C::Mixed)) => Ok(Self::AlphaNumericMixed),
             (S::Base16, None, None | Some(C::Lower)) => Ok(Self::Base16Lower),
             (S::Base16, None, Some(C::Upper)) => Ok(Self::Base16Upper),
+            (S::Base32, None, None | Some(C::Lower)) => Ok(Self::Base32CrockfordLower),
+            (S::Base32, None, Some(C::Upper)) => Ok(Self::Base32CrockfordUpper),
             (S::Base64, None, None) => Ok(Self::Base64),
             // incompatible `--char-set` and `--case` values
             (char_set_selection, _, Some(case)) => Err(format!(
@@ -167,11 +426,75 @@ impl TryFrom<(CharSetSelection, Option<CustomCharSet>, Option<Casing>)> for Char
         }
     }
 }
+impl TryFrom<(CharSetExpr, Option<CustomCharSet>, Option<Casing>)> for CharSet {
+    type Error = String;
+
+    fn try_from(combination: (CharSetExpr, Option<CustomCharSet>, Option<Casing>)) -> Result<Self, Self::Error> {
+        let (expr, custom_chars, case) = combination;
+
+        let uses_custom = expr
+            .terms
+            .iter()
+            .any(|(_, term)| matches!(term, CharSetTerm::Selection(CharSetSelection::Custom)));
+        match (uses_custom, &custom_chars) {
+            (true, None) => Err(format!("`--custom-chars` must be set to use `custom` in {expr}"))?,
+            (false, Some(_)) => Err("`--custom-chars` cannot be used unless `--char-set` includes `custom`")?,
+            _ => {}
+        }
+        let uses_case = expr
+            .terms
+            .iter()
+            .any(|(_, term)| matches!(term, CharSetTerm::Selection(_)));
+        if let (false, Some(case)) = (uses_case, case) {
+            Err(format!("the case {case:?} has no effect on the character set {expr}"))?;
+        }
+
+        let mut sets = expr
+            .terms
+            .iter()
+            .map(|(op, term)| {
+                let set = match term {
+                    CharSetTerm::Selection(CharSetSelection::Custom) => {
+                        (CharSetSelection::Custom, custom_chars.clone(), case).try_into()?
+                    }
+                    &CharSetTerm::Selection(selection) => (selection, None, case).try_into()?,
+                    &CharSetTerm::Cased(selection, case) => (selection, None, Some(case)).try_into()?,
+                    CharSetTerm::Bracket(set) => Self::Custom(set.clone()),
+                };
+                Ok::<_, String>((*op, set))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // a lone predefined set is kept as is
+        if let [(CharSetOp::Add, _)] = sets[..] {
+            return Ok(sets.remove(0).1);
+        }
+
+        let chars = sets.iter().fold(Vec::<String>::new(), |mut chars, (op, set)| {
+            let members = set.get_char_set();
+            match op {
+                CharSetOp::Add => {
+                    let new = members
+                        .into_iter()
+                        .filter(|&c| !chars.iter().any(|existing| existing == c));
+                    let new = new.map(str::to_owned).collect_vec();
+                    chars.extend(new);
+                }
+                CharSetOp::Remove => chars.retain(|c| !members.contains(&c.as_str())),
+            }
+            chars
+        });
+        if chars.is_empty() {
+            Err(format!("the character set {expr} contains no characters"))?;
+        }
+        Ok(Self::Expression { expr, chars })
+    }
+}
 impl Index<usize> for CharSet {
-    type Output = char;
+    type Output = str;
 
     fn index(&self, index: usize) -> &Self::Output {
-        &self.get_char_set()[index]
+        self.get_char_set()[index]
     }
 }
 impl fmt::Display for CharSet {
@@ -186,29 +509,59 @@ impl fmt::Display for CharSet {
             Self::AlphaNumericMixed => "[a-zA-Z0-9]".into(),
             Self::Base16Lower => "[0-9a-f]".into(),
             Self::Base16Upper => "[0-9A-F]".into(),
+            Self::Base32CrockfordLower => "[0-9a-hjkmnp-tv-z]".into(),
+            Self::Base32CrockfordUpper => "[0-9A-HJKMNP-TV-Z]".into(),
             Self::Base64 => "[A-Za-z0-9-_]".into(),
             Self::Custom(chars) => format!("Custom(\"{chars}\")"),
+            Self::Expression { expr, .. } => format!("Expression(\"{expr}\")"),
+            Self::Unambiguous { base, .. } => format!("Unambiguous({base})"),
         };
         write!(f, "{repr}")
     }
 }
 impl CharSet {
-    pub fn get_char_set(&self) -> &[char] {
-        match self {
-            Self::LettersLower => &LETTERS_L,
-            Self::LettersUpper => &LETTERS_U,
-            Self::LettersMixed => &LETTERS_M,
-            Self::Numbers => &NUMBERS,
-            Self::AlphaNumericLower => &ALPHA_NUMERIC_L,
-            Self::AlphaNumericUpper => &ALPHA_NUMERIC_U,
-            Self::AlphaNumericMixed => &ALPHA_NUMERIC_M,
-            Self::Base16Lower => &BASE_16_L,
-            Self::Base16Upper => &BASE_16_U,
-            Self::Base64 => &BASE_64,
+    /// Get the characters of this set. Each character is a single grapheme cluster.
+    pub fn get_char_set(&self) -> Vec<&str> {
+        let chars: &[String] = match self {
+            Self::LettersLower => return LETTERS_L.to_vec(),
+            Self::LettersUpper => return LETTERS_U.to_vec(),
+            Self::LettersMixed => return LETTERS_M.to_vec(),
+            Self::Numbers => return NUMBERS.to_vec(),
+            Self::AlphaNumericLower => return ALPHA_NUMERIC_L.to_vec(),
+            Self::AlphaNumericUpper => return ALPHA_NUMERIC_U.to_vec(),
+            Self::AlphaNumericMixed => return ALPHA_NUMERIC_M.to_vec(),
+            Self::Base16Lower => return BASE_16_L.to_vec(),
+            Self::Base16Upper => return BASE_16_U.to_vec(),
+            Self::Base32CrockfordLower => return BASE_32_CROCKFORD_L.to_vec(),
+            Self::Base32CrockfordUpper => return BASE_32_CROCKFORD_U.to_vec(),
+            Self::Base64 => return BASE_64.to_vec(),
             Self::Custom(set) => &set.chars,
-        }
+            Self::Expression { chars, .. } => chars,
+            Self::Unambiguous { chars, .. } => chars,
+        };
+        chars.iter().map(String::as_str).collect()
     }
     pub fn len(&self) -> usize {
         self.get_char_set().len()
     }
+
+    /// Remove all characters that are easily mistaken for one another, e.g. `0` and `O`.
+    pub fn without_ambiguous(self) -> Result<Self, String> {
+        if matches!(self, Self::Unambiguous { .. }) {
+            return Ok(self);
+        }
+        let chars = self
+            .get_char_set()
+            .into_iter()
+            .filter(|c| !AMBIGUOUS.contains(c))
+            .map(str::to_owned)
+            .collect_vec();
+        if chars.is_empty() {
+            return Err(format!("the character set {self} only contains ambiguous characters"));
+        }
+        Ok(Self::Unambiguous {
+            base: Box::new(self),
+            chars,
+        })
+    }
 }
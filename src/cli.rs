@@ -3,8 +3,12 @@ use std::{num::ParseIntError, path::PathBuf};
 use clap::{Parser, Subcommand, ValueEnum, ValueHint};
 use clap_complete::Shell;
 use clap_verbosity_flag::{Verbosity, WarnLevel};
+use globset::Glob;
 
-use crate::char_set::CustomCharSet;
+use crate::{
+    char_set::{CharSetExpr, CustomCharSet},
+    template::{NameTemplate, DEFAULT_TEMPLATE},
+};
 
 #[derive(derive_more::Debug, Clone, Parser)]
 #[command(author, version, about)]
@@ -44,12 +48,32 @@ pub struct CliArgs {
     #[arg(short = 'd', long = "dry-run")]
     pub dry_run: bool,
 
+    /// Write the mapping of original paths to new names to a file, or to stdout if `-`.
+    ///
+    /// Each record contains the original absolute path, the new name, and the outcome
+    /// of the rename (`renamed`, `previewed`, `skipped`, `failed`, `rolled-back` or `pending`).
+    /// This is written even in dry run mode, or if the run is cut short.
+    /// When writing to stdout, all other output goes to stderr instead.
+    #[arg(long = "mapping-file", value_name = "PATH", value_hint(ValueHint::FilePath))]
+    pub mapping_file: Option<PathBuf>,
+
+    /// The format of the mapping file.
+    ///
+    /// If not specified, this is guessed from the extension of `--mapping-file`,
+    /// defaulting to `json`.
+    #[arg(long = "output-format", value_name = "FORMAT", value_enum, requires = "mapping_file")]
+    pub output_format: Option<MappingFormat>,
+
     /// How to handle the original file extension?
     ///
     /// E.g. Original file name: `tarball.tar.xz`
     ///
     /// New extension: `keep-all` = `tar.xz`; `keep-last` = `xz`;
-    /// `static` = `<STATIC_EXT>`; `discard` = None
+    /// `known-compound` = `tar.xz`; `static` = `<STATIC_EXT>`; `discard` = None
+    ///
+    /// Mode `known-compound` keeps well-known compound extensions (e.g. `tar.gz`, `d.ts`) whole,
+    /// and only the last extension otherwise. More can be listed one per line in
+    /// `compound-extensions.txt` in the `rng-rename` config directory.
     ///
     /// For mode `static`, the option `--static-ext` must also be specified.
     ///
@@ -74,6 +98,16 @@ pub struct CliArgs {
     )]
     pub static_ext: Option<String>,
 
+    /// Give files in the same directory that share a stem the same random name.
+    ///
+    /// E.g. `IMG_001.CR2`, `IMG_001.jpg` and `IMG_001.xmp` all become `<RAND>.<EXT>`,
+    /// each keeping its own extension.
+    ///
+    /// The stem is whatever is left after removing the extension kept by `--ext-mode`,
+    /// which must be `keep-all`, `keep-last` or `known-compound`. The template must contain `{ext}`.
+    #[arg(long = "group-sidecars")]
+    pub group_sidecars: bool,
+
     /// How to handle errors?
     ///
     /// What to do when an error is encountered (e.g. file does not exist).
@@ -89,22 +123,148 @@ pub struct CliArgs {
     )]
     pub error_handling_mode: ErrorHandlingMode,
 
+    /// Rename either all files or none of them.
+    ///
+    /// If any rename fails, or the run is halted, every rename already performed
+    /// in this run is reverted. Failures and batches can no longer be skipped or ignored.
+    #[arg(long = "atomic")]
+    pub atomic: bool,
+
+    /// Treat names differing only in case as the same?
+    ///
+    /// On case-insensitive filesystems (e.g. FAT, exFAT, NTFS), names like `aB` and `Ab` collide.
+    /// `auto` detects this for each target directory by looking up an existing name with its case flipped.
+    /// Setting this flag without a value is the same as `always`, e.g. `--case-insensitive-fs=never`.
+    #[arg(
+        long = "case-insensitive-fs",
+        value_name = "WHEN",
+        value_enum,
+        default_value_t = CaseInsensitivity::Auto,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "always"
+    )]
+    pub case_insensitivity: CaseInsensitivity,
+
+    /// Make names valid on other platforms too.
+    ///
+    /// Names derived from the original files (e.g. `{stem}`) are sanitised to follow the rules
+    /// of the chosen platforms, while `--prefix`, `--suffix`, `--static-ext` and the character set
+    /// must already follow them. Names are also limited to 255 bytes.
+    ///
+    /// `posix` = "Only `[A-Za-z0-9._-]`, not starting with `-`";
+    /// `windows` = "No `<>:\"/\\|?*`, reserved names like `CON`, or trailing dots and spaces";
+    /// `fat` = "Same as `windows`, and no DEL"; `all` = "All of the above".
+    ///
+    /// `windows`, `fat` and `all` imply `--case-insensitive-fs`, unless it is set to `never`.
+    #[arg(long = "portable", value_name = "PLATFORM", value_enum)]
+    pub portable: Option<Portability>,
+
     /// Do not use unless you know what you're doing.
     ///
     /// Force use a specific random name generation strategy. Useful flag for testing performance.
     #[arg(long = "force-generation-strategy", value_name = "STRAT", value_enum)]
     pub force_generation_strategy: Option<NameGenerationStrategy>,
 
+    /// Seed the random number generator for reproducible results.
+    ///
+    /// Given the same seed, the same files (in the same order) and the same options,
+    /// the generated names will always be the same. If not specified, a random seed is used.
+    #[arg(long = "seed", value_name = "SEED")]
+    pub seed: Option<u64>,
+
+    /// How to generate the random part of each name?
+    ///
+    /// `char-set` = "Random characters from `--char-set`"; `uuid-v4` = "Random UUID";
+    /// `uuid-v7` = "Time-ordered UUID"; `ulid` = "Time-ordered ULID"; `nanoid` = "NanoID";
+    /// `sha256`, `blake3`, `xxhash` = "Digest of the file content";
+    /// `hmac` = "HMAC-SHA256 of the original file name"; `words` = "Random words from `--wordlist`";
+    /// `shuffle` = "Shuffled zero-padded indices"
+    ///
+    /// For identifiers (`uuid-v4`, `uuid-v7`, `ulid` and `nanoid`), each `{rand}` in the template is
+    /// replaced by a whole identifier, and `--length`, `--char-set` and `--case` have no effect.
+    ///
+    /// For digests (`sha256`, `blake3` and `xxhash`), the digest is encoded with `--char-set`
+    /// and truncated to `--length`, so files with identical content get identical names.
+    /// `xxhash` uses the 128-bit XXH3, which is fast but not cryptographic.
+    ///
+    /// For `hmac`, the digest is keyed with `--hmac-key` and encoded in the same way,
+    /// so that names are consistent across runs but cannot be linked to the originals
+    /// without the key.
+    ///
+    /// For `words`, each `{rand}` in the template is replaced by `--word-count` words
+    /// (or `N` words for `{rand:N}`), joined by `--word-separator`.
+    ///
+    /// For `shuffle`, the files are assigned the indices `0` to `n - 1` in random order,
+    /// each zero-padded to `--length` digits, so that sorting by name gives a shuffled order
+    /// without gaps.
+    #[arg(
+        short = 'g',
+        long = "generator",
+        value_name = "GEN",
+        value_enum,
+        default_value_t = GeneratorSelection::CharSet
+    )]
+    pub generator_selection: GeneratorSelection,
+
+    /// The file containing the secret key when `--generator=hmac`.
+    ///
+    /// The entire content of the file is used as the key, including any trailing newline.
+    /// E.g. generate one with `head -c 32 /dev/urandom > hmac.key`
+    #[arg(
+        long = "hmac-key",
+        value_name = "PATH",
+        value_hint(ValueHint::FilePath),
+        required_if_eq("generator_selection", "hmac")
+    )]
+    pub hmac_key: Option<PathBuf>,
+
+    /// The word list to use when `--generator=words`.
+    ///
+    /// One word per line; diceware-style lines (e.g. `11111 abacus`) are also accepted.
+    /// If not specified, the bundled BIP-0039 English word list of 2048 words is used.
+    #[arg(long = "wordlist", value_name = "PATH", value_hint(ValueHint::FilePath))]
+    pub word_list: Option<PathBuf>,
+
+    /// The number of words for each name when `--generator=words`.
+    #[arg(long = "word-count", value_name = "COUNT", default_value_t = 3)]
+    pub word_count: usize,
+
+    /// The separator between words when `--generator=words`.
+    ///
+    /// Any character that's not filename-safe will be removed.
+    #[arg(
+        long = "word-separator",
+        value_name = "SEP",
+        allow_hyphen_values = true,
+        default_value = "-"
+    )]
+    pub word_separator: String,
+
+    /// What the HMAC is computed over when `--generator=hmac`.
+    ///
+    /// `name` = "The original file name, so the same name gets the same new name in any directory";
+    /// `path` = "The original absolute path"
+    #[arg(long = "hmac-input", value_name = "INPUT", value_enum, default_value_t = HmacInput::Name)]
+    pub hmac_input: HmacInput,
+
     /// The number of random characters for each name.
     ///
     /// The number of randomly-generated characters to use for each name.
     /// This does not include the static prefix and suffix (if specified)
     /// or the file extension.
     ///
+    /// When using `--template`, this is the length of each `{rand}` placeholder
+    /// without an explicit length.
+    ///
     /// If the character set & length combination does not have enough permutations
     /// to cover all input files, the program will take no actions and fail fast.
-    #[arg(short = 'l', long = "length", value_name = "LEN", default_value = "8")]
-    pub name_length: usize,
+    /// Names that already exist in a file's directory are never chosen.
+    ///
+    /// Defaults to 8, except for `--generator=shuffle`, where it defaults to
+    /// the number of digits of the largest index.
+    #[arg(short = 'l', long = "length", value_name = "LEN")]
+    pub name_length: Option<usize>,
 
     /// Prefix each name with a static string.
     ///
@@ -118,34 +278,73 @@ pub struct CliArgs {
     #[arg(long = "suffix", value_name = "SUFFIX", allow_hyphen_values = true)]
     pub name_suffix: Option<String>,
 
+    /// Construct each name from a template.
+    ///
+    /// Available placeholders:
+    ///  - `{rand}`, `{rand:N}`: random characters, `--length` or `N` of them
+    ///  - `{stem}`: the original file name without its extension
+    ///  - `{parent}`: the name of the parent directory
+    ///  - `{index}`, `{index:N}`: the position of the file in the input list,
+    ///    starting from 0 and zero-padded to a width of `N`
+    ///  - `{date}`, `{date:FMT}`: the current date, in `strftime` format (default `%Y%m%d`)
+    ///  - `{ext}`: the new extension as determined by `--ext-mode`;
+    ///    if there is none, a `.` directly before `{ext}` is removed too
+    ///  - `{prefix}`, `{suffix}`: the values of `--prefix` and `--suffix`
+    ///
+    /// Use `{{` and `}}` for literal braces. At least one `{rand}` is required.
+    ///
+    /// Any character that's not filename-safe will be removed.
+    #[arg(
+        short = 't',
+        long = "template",
+        value_name = "TEMPLATE",
+        allow_hyphen_values = true,
+        default_value = DEFAULT_TEMPLATE,
+        verbatim_doc_comment
+    )]
+    pub template: NameTemplate,
+
     /// What random characters to use?
     ///
     /// Set the character set to use for random characters. Use `--case` to set
     /// upper, lower, or mixed case, if applicable to the character set you chose.
     ///
+    /// Predefined sets: `letters`, `numbers`, `alpha-numeric`, `base16`, `base32`, `base64`, `custom`.
+    ///
+    /// `base32` uses Crockford's alphabet, which excludes `I`, `L`, `O` and `U`.
+    ///
     /// `base64` uses base64url encoding (`[A-Za-z0-9-_]`) to be filename-safe.
     ///
     /// For mode `custom`, the option `--custom-chars` must also be specified.
+    ///
+    /// Sets can be combined with `+` and `-`, evaluated from left to right.
+    /// Operands are either predefined sets, optionally with a case suffix (e.g. `letters-upper`),
+    /// or regex-style bracket expressions (e.g. `[a-f0-9]`).
+    /// E.g. `--char-set='base16-lower-[0]'`, `--char-set='letters-lower+numbers-[0o1l]'`
     #[arg(
         short = 's',
         long = "char-set",
         alias = "charset",
-        value_name = "SET",
-        value_enum,
-        default_value_t = CharSetSelection::Base16
+        value_name = "EXPR",
+        default_value = "base16"
     )]
-    pub char_set_selection: CharSetSelection,
+    pub char_set_expr: CharSetExpr,
 
     /// The character set to use when `--char-set=custom`.
     ///
     /// E.g. `--custom-chars=ABCDabcd`
     ///
     /// Inclusion of any character that's not filename-safe will cause an error.
+    ///
+    /// Each grapheme cluster counts as one character, so emoji and accented letters
+    /// made of several code points are never split. Characters that are the same after
+    /// Unicode normalisation or case folding (e.g. `é` written in NFC and NFD) are rejected,
+    /// except for plain upper and lower case pairs like `A` and `a`.
     #[arg(
         long = "custom-chars",
         value_name = "CHARS",
         allow_hyphen_values = true,
-        required_if_eq("char_set_selection", "custom")
+        required_if_eq("char_set_expr", "custom")
     )]
     pub custom_chars: Option<CustomCharSet>,
 
@@ -157,11 +356,73 @@ pub struct CliArgs {
     /// will take no actions and fail fast.
     ///
     /// Support table: `letters` - `upper|lower|mixed`; `numbers` - N/A;
-    /// `alpha-numeric` - `upper|lower|mixed`; `base16` - `upper|lower`; `base64` - N/A;
-    /// `custom` - N/A.
+    /// `alpha-numeric` - `upper|lower|mixed`; `base16` - `upper|lower`; `base32` - `upper|lower`;
+    /// `base64` - N/A; `custom` - N/A.
     #[arg(long = "case", value_name = "CASE")]
     pub case: Option<Casing>,
 
+    /// Remove visually ambiguous characters from the character set.
+    ///
+    /// The characters `0`, `O`, `o`, `1`, `I`, `l`, `5` and `S` are never used,
+    /// so that names can be read off a screen and typed reliably.
+    #[arg(long = "no-ambiguous")]
+    pub no_ambiguous: bool,
+
+    /// Read the files to rename from a file, or from stdin if `-`.
+    ///
+    /// One path per line, or separated by NUL if `--null` is set.
+    /// These are added after the files specified on the command line.
+    ///
+    /// Since prompts also read stdin, `-` requires `--confirm none`
+    /// and `--error-handling-mode` to be `ignore` or `halt`.
+    #[arg(long = "files-from", value_name = "PATH", value_hint(ValueHint::FilePath))]
+    pub files_from: Option<PathBuf>,
+
+    /// Paths read by `--files-from` are separated by NUL instead of newlines.
+    ///
+    /// Use this with `find -print0`, `fd -0` and the like to safely handle file names
+    /// containing newlines.
+    #[arg(short = '0', long = "null", requires = "files_from")]
+    pub null_separated: bool,
+
+    /// Rename the files inside directories, instead of the directories themselves.
+    ///
+    /// Each directory is expanded into all files beneath it, recursively.
+    /// Symlinks beneath the directories are skipped, since they may point outside of them.
+    #[arg(short = 'r', long = "recursive")]
+    pub recursive: bool,
+
+    /// Only rename files matching this glob pattern. Can be specified multiple times.
+    ///
+    /// The pattern is matched against the path relative to the directory being expanded,
+    /// or the file name for files that are specified directly. E.g. `--include='*.jpg'`
+    #[arg(
+        long = "include",
+        value_name = "GLOB",
+        allow_hyphen_values = true,
+        requires = "recursive",
+        value_parser = Glob::new
+    )]
+    pub include: Vec<Glob>,
+
+    /// Do not rename files matching this glob pattern. Can be specified multiple times.
+    ///
+    /// Matched in the same way as `--include`. Takes precedence over `--include`.
+    #[arg(
+        long = "exclude",
+        value_name = "GLOB",
+        allow_hyphen_values = true,
+        requires = "recursive",
+        value_parser = Glob::new
+    )]
+    pub exclude: Vec<Glob>,
+
+    /// The maximum depth to descend into directories.
+    ///
+    /// `1` means only the files directly inside each directory are renamed.
+    #[arg(long = "max-depth", value_name = "DEPTH", requires = "recursive")]
+    pub max_depth: Option<usize>,
+
     #[command(flatten)]
     pub verbosity: Verbosity<WarnLevel>,
 
@@ -178,7 +439,7 @@ pub struct CliArgs {
     ///  - Run `rng-rename --length 5 -- -file-1 -file-2`
     #[debug("/* omitted */")]
     #[arg(
-        required = true,
+        required_unless_present = "files_from",
         value_name = "FILES",
         value_hint(ValueHint::AnyPath),
         verbatim_doc_comment
@@ -197,6 +458,43 @@ pub enum SubCmd {
         #[arg(required = true, value_name = "SHELL", value_enum)]
         shell_type: Shell,
     },
+
+    /// Rename files according to a mapping file, skipping name generation entirely.
+    ///
+    /// The mapping file uses the same format as written by `--mapping-file`;
+    /// only the `path` and `new_name` fields are required. Relative paths are resolved
+    /// against the current directory.
+    ///
+    /// Options such as `--dry-run`, `--confirm` and `--error-handling-mode` still apply,
+    /// but must be specified before the subcommand. For example:
+    /// `rng-rename --dry-run apply mapping.csv`
+    Apply {
+        /// The mapping file to apply, or `-` to read from stdin.
+        #[arg(required = true, value_name = "MAPPING", value_hint(ValueHint::FilePath))]
+        mapping: PathBuf,
+
+        /// The format of the mapping file.
+        ///
+        /// If not specified, this is guessed from the file extension, defaulting to `json`.
+        #[arg(long = "format", value_name = "FORMAT", value_enum)]
+        format: Option<MappingFormat>,
+    },
+
+    /// Undo a previous run by replaying its journal in reverse.
+    ///
+    /// Every run that is not a dry run writes a journal of the renames it performed
+    /// to the state directory (e.g. `~/.local/state/rng-rename/journal` on Linux).
+    /// Each rename is written as soon as it is performed, so an interrupted run can be undone too.
+    /// Once all its renames have been reverted, the journal is removed.
+    ///
+    /// Options such as `--dry-run`, `--confirm` and `--error-handling-mode` still apply,
+    /// but must be specified before the subcommand. For example:
+    /// `rng-rename --dry-run undo`
+    Undo {
+        /// The journal to undo. Defaults to the most recently written journal.
+        #[arg(value_name = "JOURNAL", value_hint(ValueHint::FilePath))]
+        journal: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
@@ -207,11 +505,20 @@ pub enum ConfirmMode {
     Each,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "kebab-case")]
+pub enum MappingFormat {
+    Json,
+    Csv,
+    Tsv,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
 #[value(rename_all = "kebab-case")]
 pub enum ExtensionModeSelection {
     KeepAll,
     KeepLast,
+    KnownCompound,
     Static,
     Discard,
 }
@@ -231,6 +538,47 @@ pub enum NameGenerationStrategy {
     Match,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "kebab-case")]
+pub enum GeneratorSelection {
+    CharSet,
+    UuidV4,
+    UuidV7,
+    Ulid,
+    #[value(name = "nanoid")]
+    NanoId,
+    Sha256,
+    Blake3,
+    Xxhash,
+    Hmac,
+    Words,
+    Shuffle,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "kebab-case")]
+pub enum HmacInput {
+    Name,
+    Path,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "kebab-case")]
+pub enum CaseInsensitivity {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "kebab-case")]
+pub enum Portability {
+    Posix,
+    Windows,
+    Fat,
+    All,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
 #[value(rename_all = "kebab-case")]
 pub enum CharSetSelection {
@@ -239,6 +587,7 @@ pub enum CharSetSelection {
     Numbers,
     AlphaNumeric,
     Base16,
+    Base32,
     Base64,
 }
 
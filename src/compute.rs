@@ -1,19 +1,33 @@
 use std::{
-    fmt, iter,
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::File,
+    io, iter,
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+use caseless::default_case_fold_str;
+use chrono::{DateTime, Local};
+use hmac::{Hmac, Mac};
 use itertools::Itertools;
-use log::{debug, info, trace};
-use rand::Rng;
+use log::{debug, info, trace, warn};
+use rand::{seq::SliceRandom, Rng};
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
 use yansi::Paint;
 
 use crate::{
     char_set::CharSet,
-    cli::{ErrorHandlingMode, NameGenerationStrategy},
-    util::{error_prompt, ExtensionMode, OnErrorResponse},
+    cli::{ErrorHandlingMode, GeneratorSelection, HmacInput, NameGenerationStrategy, Portability},
+    template::{NameTemplate, Placeholder, TemplatePart},
+    util::{error_prompt, message, sanitise_name, sanitise_os_name, ExtensionMode, OnErrorResponse},
+    word_list::WordList,
 };
 
+/// The length of each `{rand}` placeholder when `--length` is not specified.
+pub const DEFAULT_NAME_LENGTH: usize = 8;
 /// The hard-coded limit for the number of files that can be processed at once.
 const FILE_COUNT_MAX: usize = 2usize.pow(20);
 /// The hard-coded limit for the number of permutations that can be generated first.
@@ -21,12 +35,53 @@ const PERMUTATION_COUNT_MAX: usize = 2usize.pow(24);
 /// The ratio of files to naming space at which we switch from
 /// `generate_on_demand` to `generate_then_match`.
 const STRATEGY_RATIO_THRESHOLD: f64 = 0.6; // TODO: see `Errata.md`
+/// The number of consecutive collisions after which `generate_on_demand` gives up on a file.
+const COLLISION_RETRY_MAX: usize = 2usize.pow(10);
+/// Separates the symbols (characters or words) of a random name until it is finalised,
+/// since adjacent characters may merge into one grapheme cluster (e.g. regional indicators).
+/// This is never part of a symbol, since word lists have one word per line
+/// and character sets cannot contain control characters.
+const SYMBOL_DELIMITER: &str = "\n";
 
 #[derive(Debug, Clone)]
 pub enum NameGenerationError {
-    InsufficientNamingSpace { needs: usize, space: usize },
-    TooManyFiles { count: usize },
-    TooManyPermutations { char_set: CharSet, length: usize },
+    InsufficientNamingSpace {
+        needs: usize,
+        space: usize,
+    },
+    TooManyFiles {
+        count: usize,
+    },
+    TooManyPermutations {
+        symbol_count: usize,
+        length: usize,
+    },
+    NoUnusedName {
+        path: PathBuf,
+    },
+    FixedLengthIdentifier {
+        kind: IdentifierKind,
+    },
+    DigestTooShort {
+        source: DigestSource,
+        char_set: CharSet,
+        max: usize,
+    },
+    DigestCollision {
+        path: PathBuf,
+        name: OsString,
+    },
+    EmptyHmacKey,
+    IndexCollision {
+        path: PathBuf,
+        name: OsString,
+    },
+    /// Two sidecar files would receive the same name, e.g. `IMG.jpg` and `IMG.JPG` in a case-insensitive directory.
+    SidecarCollision {
+        path: PathBuf,
+        other: PathBuf,
+        name: OsString,
+    },
 }
 impl From<NameGenerationError> for String {
     fn from(err: NameGenerationError) -> Self {
@@ -38,141 +93,734 @@ impl fmt::Display for NameGenerationError {
         let repr = match self {
             Self::InsufficientNamingSpace { needs, space } => {
                 format!(
-                    "This combination of character set and length cannot uniquely cover every file.\n\
+                    "This combination of character set (or word list) and length cannot uniquely cover every file.\n\
                     There are {needs} files but only {space} unique names available."
                 )
             }
             Self::TooManyFiles { count } => {
                 format!("Cannot process {count} files at once. Currently the limit is {FILE_COUNT_MAX}.")
             }
-            Self::TooManyPermutations { char_set, length } => {
-                format!("Cannot enumerate all permutations with the character set {char_set} and length {length}.")
+            Self::TooManyPermutations { symbol_count, length } => {
+                format!("Cannot enumerate all permutations of {length} out of {symbol_count} characters or words.")
+            }
+            Self::NoUnusedName { path } => {
+                format!(
+                    "Cannot find an unused name for {path:?}.\n\
+                    Too many names of this character set (or word list) and length are already taken in its directory."
+                )
+            }
+            Self::FixedLengthIdentifier { kind } => {
+                format!("Every {kind} has a fixed length, so it cannot be used with `{{rand:N}}` in the template.")
+            }
+            Self::DigestTooShort { source, char_set, max } => {
+                format!(
+                    "A digest of {source} encoded with the character set {char_set} is at most {max} characters long."
+                )
+            }
+            Self::DigestCollision { path, name } => {
+                format!(
+                    "The derived name {name:?} for {path:?} is already taken in its directory.\n\
+                    The file may be a duplicate, or the digest may be truncated too much."
+                )
+            }
+            Self::EmptyHmacKey => "The HMAC key must not be empty.".into(),
+            Self::IndexCollision { path, name } => {
+                format!(
+                    "The shuffled name {name:?} for {path:?} is already taken in its directory.\n\
+                    Every index must be used, so move the file with this name out of the way and retry."
+                )
+            }
+            Self::SidecarCollision { path, other, name } => {
+                format!(
+                    "{path:?} and its sidecar {other:?} would both be named {name:?} in their directory.\n\
+                    Rename one of them separately, without `--group-sidecars`."
+                )
             }
         };
         write!(f, "{repr}")
     }
 }
 
-/// Generate random names and match them to each file.
-pub fn generate_random_names<P>(
-    files: &[P],
-    chars: CharSet,
-    length: usize,
+/// The names already present in each directory.
+pub type TakenNames = HashMap<PathBuf, DirNames>;
+
+/// The names already present in a single directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirNames {
+    names: HashSet<OsString>,
+    /// Whether names that differ only in case refer to the same file in this directory.
+    case_insensitive: bool,
+}
+impl DirNames {
+    pub fn new(names: impl IntoIterator<Item = OsString>, case_insensitive: bool) -> Self {
+        let mut dir_names = Self {
+            names: HashSet::new(),
+            case_insensitive,
+        };
+        for name in names {
+            dir_names.insert(&name);
+        }
+        dir_names
+    }
+
+    /// The form of `name` this directory considers unique.
+    fn key(&self, name: &OsStr) -> OsString {
+        if self.case_insensitive {
+            fold_name(name)
+        } else {
+            name.to_owned()
+        }
+    }
+
+    pub fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+    pub fn contains(&self, name: &OsStr) -> bool {
+        self.names.contains(&self.key(name))
+    }
+    /// Add a name, returning whether it was not already present.
+    pub fn insert(&mut self, name: &OsStr) -> bool {
+        self.names.insert(self.key(name))
+    }
+    /// Remove a name, returning whether it was present.
+    pub fn remove(&mut self, name: &OsStr) -> bool {
+        self.names.remove(&self.key(name))
+    }
+}
+
+/// Case fold a name, so that names that differ only in case are equal.
+///
+/// Names that are not valid UTF-8 are never case folded.
+pub fn fold_name(name: &OsStr) -> OsString {
+    match name.to_str() {
+        Some(name) => default_case_fold_str(name).into(),
+        None => name.to_owned(),
+    }
+}
+
+/// A standard identifier format.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdentifierKind {
+    /// A random UUID, in lowercase hyphenated form.
+    UuidV4,
+    /// A time-ordered UUID, in lowercase hyphenated form.
+    UuidV7,
+    /// A time-ordered ULID, in Crockford base32.
+    Ulid,
+    /// A NanoID of the default size and alphabet.
+    NanoId,
+}
+impl fmt::Display for IdentifierKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Self::UuidV4 => "UUIDv4",
+            Self::UuidV7 => "UUIDv7",
+            Self::Ulid => "ULID",
+            Self::NanoId => "NanoID",
+        };
+        write!(f, "{repr}")
+    }
+}
+impl IdentifierKind {
+    /// The number of characters in every identifier of this kind.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::UuidV4 | Self::UuidV7 => 36,
+            Self::Ulid => 26,
+            Self::NanoId => 21,
+        }
+    }
+
+    /// Generate a single identifier.
+    ///
+    /// All random bits are drawn from `rng`, so that seeded runs stay reproducible
+    /// (apart from the timestamp of time-ordered identifiers).
+    fn generate(&self, rng: &mut impl Rng) -> String {
+        let unix_millis = || {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default()
+        };
+        match self {
+            Self::UuidV4 => uuid::Builder::from_random_bytes(rng.gen()).into_uuid().to_string(),
+            Self::UuidV7 => uuid::Builder::from_unix_timestamp_millis(unix_millis(), &rng.gen())
+                .into_uuid()
+                .to_string(),
+            Self::Ulid => ulid::Ulid::from_parts(unix_millis(), rng.gen()).to_string(),
+            // the NanoID alphabet is exactly the base64url alphabet
+            Self::NanoId => {
+                let chars = CharSet::Base64;
+                (0..self.len()).map(|_| &chars[rng.gen_range(0..chars.len())]).collect()
+            }
+        }
+    }
+}
+
+/// A hash function used to derive names from file content.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Sha256,
+    Blake3,
+    /// The 128-bit variant of XXH3.
+    Xxh3,
+}
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Self::Sha256 => "SHA-256",
+            Self::Blake3 => "BLAKE3",
+            Self::Xxh3 => "XXH3-128",
+        };
+        write!(f, "{repr}")
+    }
+}
+impl DigestAlgorithm {
+    /// The number of bytes in every digest.
+    fn digest_len(&self) -> usize {
+        match self {
+            Self::Sha256 | Self::Blake3 => 32,
+            Self::Xxh3 => 16,
+        }
+    }
+
+    /// Hash the content of a file.
+    fn digest_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut file = File::open(path)?;
+        let digest = match self {
+            Self::Sha256 => {
+                let mut hasher = Sha256::default();
+                io::copy(&mut file, &mut hasher)?;
+                hasher.finalize().to_vec()
+            }
+            Self::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                io::copy(&mut file, &mut hasher)?;
+                hasher.finalize().as_bytes().to_vec()
+            }
+            Self::Xxh3 => {
+                let mut hasher = Xxh3::new();
+                io::copy(&mut file, &mut hasher)?;
+                hasher.digest128().to_be_bytes().to_vec()
+            }
+        };
+        Ok(digest)
+    }
+}
+
+/// The number of characters needed to encode `byte_count` bytes using a character set of size `base`.
+fn encoded_len(byte_count: usize, base: usize) -> usize {
+    if base < 2 {
+        return 0;
+    }
+    ((byte_count * 8) as f64 / (base as f64).log2()).ceil() as usize
+}
+
+/// Encode a big-endian number using `chars` as the digits, most significant digit first.
+///
+/// The result is zero-padded to the full width of `bytes`, so that a prefix of the encoding
+/// is the same as with common tools (e.g. hex for base16).
+fn encode_with_char_set<'a>(bytes: &[u8], chars: &'a CharSet) -> Vec<&'a str> {
+    let base = chars.len() as u32;
+    let mut num = bytes.to_vec();
+    let mut digits = vec![];
+    for _ in 0..encoded_len(bytes.len(), chars.len()) {
+        // long division, keeping the remainder as the next digit
+        let mut rem = 0;
+        for byte in num.iter_mut() {
+            let acc = (rem << 8) | *byte as u32;
+            *byte = (acc / base) as u8;
+            rem = acc % base;
+        }
+        digits.push(&chars[rem as usize]);
+    }
+    digits.reverse();
+    digits
+}
+
+/// What a name is derived from, when it is not random.
+#[derive(derive_more::Debug, Clone, PartialEq, Eq)]
+pub enum DigestSource {
+    /// The file content, hashed with `algorithm`.
+    Content(DigestAlgorithm),
+    /// The original file name or path, keyed with HMAC-SHA256.
+    Hmac {
+        #[debug("/* redacted */")]
+        key: Vec<u8>,
+        input: HmacInput,
+    },
+}
+impl fmt::Display for DigestSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Self::Content(algorithm) => format!("the file content using {algorithm}"),
+            Self::Hmac {
+                input: HmacInput::Name, ..
+            } => "the file name using HMAC-SHA256".into(),
+            Self::Hmac {
+                input: HmacInput::Path, ..
+            } => "the file path using HMAC-SHA256".into(),
+        };
+        write!(f, "{repr}")
+    }
+}
+impl DigestSource {
+    /// The number of bytes in every digest.
+    fn digest_len(&self) -> usize {
+        match self {
+            Self::Content(algorithm) => algorithm.digest_len(),
+            Self::Hmac { .. } => 32,
+        }
+    }
+
+    /// Compute the digest for a file.
+    fn digest(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Content(algorithm) => algorithm.digest_file(path),
+            Self::Hmac { key, input } => {
+                let message = match input {
+                    HmacInput::Name => path.file_name().expect("paths should already be canonicalised"),
+                    HmacInput::Path => path.as_os_str(),
+                };
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC should accept keys of any length");
+                mac.update(message.as_encoded_bytes());
+                Ok(mac.finalize().into_bytes().to_vec())
+            }
+        }
+    }
+}
+
+/// How the random part of each name is generated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameGenerator {
+    /// Random characters from a character set, `length` of them in total.
+    CharSet { chars: CharSet, length: usize },
+    /// A whole identifier for each of the `count` `{rand}` placeholders.
+    Identifier { kind: IdentifierKind, count: usize },
+    /// Random words from a word list, `count` of them in total.
+    Words { words: WordList, count: usize },
+    /// A shuffled index, zero-padded to each width, for each `{rand}` placeholder.
+    Shuffle { widths: Vec<usize> },
+    /// A digest of each file encoded with a character set,
+    /// truncated to `length` characters in total.
+    Digest {
+        source: DigestSource,
+        chars: CharSet,
+        length: usize,
+    },
+}
+impl fmt::Display for NameGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Self::CharSet { chars, length } => format!("{length} characters of {chars}"),
+            Self::Identifier { kind, count } => format!("{count} {kind}"),
+            Self::Words { words, count } => format!("{count} words from a list of {}", words.len()),
+            Self::Shuffle { widths } => format!("shuffled indices padded to {widths:?} digits"),
+            Self::Digest { source, chars, length } => {
+                format!("{length} characters of a digest of {source} encoded with {chars}")
+            }
+        };
+        write!(f, "{repr}")
+    }
+}
+impl NameGenerator {
+    /// Create the generator chosen by `--generator` for names constructed from `template`.
+    ///
+    /// `name_length` is the length of each `{rand}` placeholder without an explicit length,
+    /// in characters for character sets and digests, or in words for word lists.
+    /// `word_list` defaults to the bundled word list.
+    /// `hmac_key` and `hmac_input` are only applicable to HMAC.
+    pub fn new(
+        selection: GeneratorSelection,
+        chars: CharSet,
+        word_list: Option<WordList>,
+        template: &NameTemplate,
+        name_length: usize,
+        hmac_key: Option<Vec<u8>>,
+        hmac_input: HmacInput,
+    ) -> Result<Self, NameGenerationError> {
+        let kind = match selection {
+            GeneratorSelection::CharSet => {
+                return Ok(Self::CharSet {
+                    chars,
+                    length: template.random_length(name_length),
+                })
+            }
+            GeneratorSelection::Words => {
+                return Ok(Self::Words {
+                    words: word_list.unwrap_or_else(WordList::bundled),
+                    count: template.random_length(name_length),
+                })
+            }
+            GeneratorSelection::Shuffle => {
+                let widths = template
+                    .placeholders()
+                    .filter_map(|p| match p {
+                        Placeholder::Random(len) => Some(len.unwrap_or(name_length)),
+                        _ => None,
+                    })
+                    .collect();
+                return Ok(Self::Shuffle { widths });
+            }
+            GeneratorSelection::Sha256
+            | GeneratorSelection::Blake3
+            | GeneratorSelection::Xxhash
+            | GeneratorSelection::Hmac => {
+                let source = match selection {
+                    GeneratorSelection::Sha256 => DigestSource::Content(DigestAlgorithm::Sha256),
+                    GeneratorSelection::Blake3 => DigestSource::Content(DigestAlgorithm::Blake3),
+                    GeneratorSelection::Xxhash => DigestSource::Content(DigestAlgorithm::Xxh3),
+                    _ => {
+                        let key = hmac_key.expect("`--hmac-key` should be required by clap");
+                        if key.is_empty() {
+                            return Err(NameGenerationError::EmptyHmacKey);
+                        }
+                        DigestSource::Hmac { key, input: hmac_input }
+                    }
+                };
+                let length = template.random_length(name_length);
+                let max = encoded_len(source.digest_len(), chars.len());
+                if length > max {
+                    return Err(NameGenerationError::DigestTooShort {
+                        source,
+                        char_set: chars,
+                        max,
+                    });
+                }
+                return Ok(Self::Digest { source, chars, length });
+            }
+            GeneratorSelection::UuidV4 => IdentifierKind::UuidV4,
+            GeneratorSelection::UuidV7 => IdentifierKind::UuidV7,
+            GeneratorSelection::Ulid => IdentifierKind::Ulid,
+            GeneratorSelection::NanoId => IdentifierKind::NanoId,
+        };
+
+        let mut count = 0;
+        for placeholder in template.placeholders() {
+            match placeholder {
+                Placeholder::Random(None) => count += 1,
+                Placeholder::Random(Some(_)) => Err(NameGenerationError::FixedLengthIdentifier { kind })?,
+                _ => {}
+            }
+        }
+        Ok(Self::Identifier { kind, count })
+    }
+
+    /// The length of each `{rand}` placeholder without an explicit length.
+    pub fn placeholder_length(&self, name_length: usize) -> usize {
+        match self {
+            Self::CharSet { .. } | Self::Words { .. } | Self::Shuffle { .. } | Self::Digest { .. } => name_length,
+            Self::Identifier { kind, .. } => kind.len(),
+        }
+    }
+}
+
+/// Generate random names, finalise them, and match them to each file.
+/// Files in the same group (see `NameParts::group`) share a random name.
+///
+/// The finalised names never collide with each other, nor with any name in `taken`
+/// in the same directory. Every chosen name is added to `taken`.
+///
+/// Given the same `rng` state, the same files will always be matched to the same names.
+pub fn generate_random_names<'a, P>(
+    files: &'a [(P, NameParts)],
+    generator: &NameGenerator,
     force_strategy: Option<NameGenerationStrategy>,
-) -> Result<Vec<(&Path, String)>, NameGenerationError>
+    rng: &mut impl Rng,
+    finaliser: &NameFinaliser,
+    taken: &mut TakenNames,
+) -> Result<Vec<(&'a Path, OsString)>, NameGenerationError>
 where
     P: AsRef<Path>,
 {
+    let groups = group_files(files);
+    if groups.len() < files.len() {
+        debug!(
+            "Grouped {} files into {} groups of sidecars.",
+            files.len(),
+            groups.len()
+        );
+    }
+
+    // random names are sequences of symbols, i.e. characters or words
+    let (symbols, length) = match generator {
+        NameGenerator::CharSet { chars, length } => {
+            let symbols = chars.get_char_set().into_iter().map(str::to_owned).collect_vec();
+            (symbols, *length)
+        }
+        NameGenerator::Words { words, count } => (words.words().to_vec(), *count),
+        NameGenerator::Identifier { kind, count } => {
+            // the naming space of identifiers is practically infinite
+            if force_strategy == Some(NameGenerationStrategy::Match) {
+                warn!("Cannot enumerate every {kind}; ignoring the forced strategy.");
+            }
+            trace!("Checking the number of files does not exceed the maximum.");
+            if groups.len() > FILE_COUNT_MAX {
+                return Err(NameGenerationError::TooManyFiles { count: groups.len() });
+            }
+            let next_random_name = || {
+                (0..*count)
+                    .map(|_| kind.generate(rng).chars().join(SYMBOL_DELIMITER))
+                    .join(SYMBOL_DELIMITER)
+            };
+            return generate_on_demand(&groups, next_random_name, finaliser, taken);
+        }
+        NameGenerator::Digest { chars, length, .. } => {
+            if force_strategy.is_some() {
+                warn!("Names are not random; ignoring the forced strategy.");
+            }
+            return generate_from_digests(&groups, chars, *length, finaliser, taken);
+        }
+        NameGenerator::Shuffle { widths } => {
+            if force_strategy.is_some() {
+                warn!("Names are shuffled indices; ignoring the forced strategy.");
+            }
+            return generate_shuffled(&groups, widths, rng, finaliser, taken);
+        }
+    };
+
     trace!("Checking if there are enough permutations.");
-    let naming_spaces_size = chars.len().pow(length as u32);
-    if files.len() > naming_spaces_size {
+    // on a case-insensitive filesystem, symbols differing only in case produce the same names
+    let distinct_symbol_count = if taken.values().any(DirNames::is_case_insensitive) {
+        symbols.iter().map(|s| default_case_fold_str(s)).unique().count()
+    } else {
+        symbols.len()
+    };
+    let naming_spaces_size = distinct_symbol_count.saturating_pow(length as u32);
+    if groups.len() > naming_spaces_size {
         return Err(NameGenerationError::InsufficientNamingSpace {
-            needs: files.len(),
+            needs: groups.len(),
             space: naming_spaces_size,
         });
     }
 
     trace!("Checking the number of files does not exceed the maximum.");
-    if files.len() > FILE_COUNT_MAX {
-        return Err(NameGenerationError::TooManyFiles { count: files.len() });
+    if groups.len() > FILE_COUNT_MAX {
+        return Err(NameGenerationError::TooManyFiles { count: groups.len() });
     }
 
+    let next_random_name = || {
+        (0..length)
+            .map(|_| &symbols[rng.gen_range(0..symbols.len())])
+            .join(SYMBOL_DELIMITER)
+    };
     match force_strategy {
         Some(NameGenerationStrategy::OnDemand) => {
             debug!("Forcing \"generate on demand\" strategy.");
-            generate_on_demand(files, chars, length)
+            generate_on_demand(&groups, next_random_name, finaliser, taken)
         }
         Some(NameGenerationStrategy::Match) => {
             debug!("Forcing \"generate_then_match\" strategy.");
-            generate_then_match(files, chars, length)
+            generate_then_match(&groups, &symbols, length, rng, finaliser, taken)
         }
         None => {
-            let files_space_ratio = (files.len() as f64) / (naming_spaces_size as f64);
+            // existing names may collide too, so they take up naming space as well
+            let taken_count: usize = taken.values().map(DirNames::len).sum();
+            let files_space_ratio = ((groups.len() + taken_count) as f64) / (naming_spaces_size as f64);
             trace!("Ratio of files to naming space is {files_space_ratio:.2e}.");
             if files_space_ratio < STRATEGY_RATIO_THRESHOLD {
-                generate_on_demand(files, chars, length)
+                generate_on_demand(&groups, next_random_name, finaliser, taken)
             } else {
-                generate_then_match(files, chars, length)
+                generate_then_match(&groups, &symbols, length, rng, finaliser, taken)
             }
         }
     }
 }
 
-/// Generate each random string independently. Potential collisions
-/// are resolved on demand by regenerating.
+/// Get the set of names already taken in the directory containing `path`.
+fn taken_in_dir<'t>(taken: &'t mut TakenNames, path: &Path) -> &'t mut DirNames {
+    let dir = path.parent().expect("paths should point to files at this point");
+    taken.entry(dir.to_owned()).or_default()
+}
+
+/// Files that receive the same random name, all within the same directory.
+type FileGroup<'a> = Vec<(&'a Path, &'a NameParts)>;
+
+/// Gather files into groups by `NameParts::group`, in the order each group first appears.
+fn group_files<P>(files: &[(P, NameParts)]) -> Vec<FileGroup<'_>>
+where
+    P: AsRef<Path>,
+{
+    let mut groups: Vec<FileGroup> = vec![];
+    let mut positions = HashMap::new();
+    for (file, parts) in files.iter() {
+        let pos = *positions.entry(parts.group).or_insert_with(|| {
+            groups.push(vec![]);
+            groups.len() - 1
+        });
+        groups[pos].push((file.as_ref(), parts));
+    }
+    groups
+}
+
+/// Why the finalised names of a group cannot be used.
+enum GroupConflict<'a> {
+    /// A name is already taken in the directory, so another random name may work.
+    Taken(&'a Path, OsString),
+    /// Two files in the group receive the same name, whatever the random name.
+    Collision(NameGenerationError),
+}
+impl GroupConflict<'_> {
+    /// Turn the conflict into an error, using `taken_error` if a name is taken.
+    fn into_error(self, taken_error: impl FnOnce(PathBuf, OsString) -> NameGenerationError) -> NameGenerationError {
+        match self {
+            Self::Taken(path, name) => taken_error(path.to_owned(), name),
+            Self::Collision(err) => err,
+        }
+    }
+}
+
+/// Finalise the names of every file in `group` using the same random name.
+///
+/// The names must not be taken in the directory, nor be the same as each other (as the directory sees it).
+fn finalise_group<'a>(
+    group: &FileGroup<'a>,
+    random_name: &str,
+    finaliser: &NameFinaliser,
+    dir_taken: &DirNames,
+) -> Result<Vec<(&'a Path, OsString)>, GroupConflict<'a>> {
+    // keyed the same way as `dir_taken`, so that names differing only in case collide if they would there
+    let mut group_names: HashMap<OsString, &Path> = HashMap::new();
+    let mut names = vec![];
+    for &(path, parts) in group.iter() {
+        let name = finaliser.finalise(parts, random_name);
+        if dir_taken.contains(&name) {
+            return Err(GroupConflict::Taken(path, name));
+        }
+        if let Some(&other) = group_names.get(&dir_taken.key(&name)) {
+            return Err(GroupConflict::Collision(NameGenerationError::SidecarCollision {
+                path: path.to_owned(),
+                other: other.to_owned(),
+                name,
+            }));
+        }
+        group_names.insert(dir_taken.key(&name), path);
+        names.push((path, name));
+    }
+    Ok(names)
+}
+
+/// Generate each random string independently using `next_random_name`.
+/// Potential collisions are resolved on demand by regenerating.
 ///
 /// Use when the naming space is large and the files are few.
-fn generate_on_demand(
-    files: &[impl AsRef<Path>],
-    chars: CharSet,
-    length: usize,
-) -> Result<Vec<(&Path, String)>, NameGenerationError> {
+fn generate_on_demand<'a>(
+    groups: &[FileGroup<'a>],
+    mut next_random_name: impl FnMut() -> String,
+    finaliser: &NameFinaliser,
+    taken: &mut TakenNames,
+) -> Result<Vec<(&'a Path, OsString)>, NameGenerationError> {
     info!("Using \"Generate on demand\" strategy.");
 
-    let mut rng = rand::thread_rng();
-
+    let mut used_random_names = HashSet::new();
     let mut name_map = vec![];
     trace!("Generating names for every file.");
-    for file in files.iter() {
+    for group in groups.iter() {
+        let dir_taken = taken_in_dir(taken, group[0].0);
+
         // loop until an unused name is found
-        let name = loop {
-            let mut name = String::new();
-            // push random characters into name
-            for _ in 0..length {
-                name.push(chars[rng.gen_range(0..chars.len())]);
+        let mut collision_count = 0;
+        let names = loop {
+            if collision_count > COLLISION_RETRY_MAX {
+                return Err(NameGenerationError::NoUnusedName {
+                    path: group[0].0.to_owned(),
+                });
             }
+
+            let random_name = next_random_name();
             // check if name is used
-            if name_map.iter().any(|(_, existing_name)| existing_name == &name) {
-                debug!("Random name collision: \"{name}\". Retrying");
-            } else {
-                break name;
+            if used_random_names.contains(&random_name) {
+                debug!("Random name collision: {random_name:?}. Retrying");
+                collision_count += 1;
+                continue;
+            }
+            match finalise_group(group, &random_name, finaliser, dir_taken) {
+                Ok(names) => {
+                    used_random_names.insert(random_name);
+                    break names;
+                }
+                Err(GroupConflict::Taken(path, name)) => {
+                    debug!("{name:?} is already taken in the directory of {path:?}. Retrying");
+                    collision_count += 1;
+                }
+                Err(GroupConflict::Collision(err)) => return Err(err),
             }
         };
-        name_map.push((file.as_ref(), name));
+        for (path, name) in names {
+            dir_taken.insert(&name);
+            name_map.push((path, name));
+        }
     }
 
-    debug!("Generated {} random names.", files.len());
+    debug!("Generated {} random names.", name_map.len());
     trace!("Pairs: {name_map:?}");
     Ok(name_map)
 }
 
-/// Generate all possible permutations first, then match them to files.
+/// Generate all possible permutations of `symbols` first,
+/// then match them to files.
 ///
 /// Use when the naming space is on the same order of magnitude as
 /// the number of files.
-fn generate_then_match(
-    files: &[impl AsRef<Path>],
-    chars: CharSet,
+fn generate_then_match<'a>(
+    groups: &[FileGroup<'a>],
+    symbols: &[String],
     length: usize,
-) -> Result<Vec<(&Path, String)>, NameGenerationError> {
+    rng: &mut impl Rng,
+    finaliser: &NameFinaliser,
+    taken: &mut TakenNames,
+) -> Result<Vec<(&'a Path, OsString)>, NameGenerationError> {
     info!("Using \"Generate then match\" strategy.");
 
     // check if the number of permutations is too large
     trace!("Checking if the number of permutations is too large.");
-    let permutation_count = chars.len().checked_pow(length as u32);
+    let permutation_count = symbols.len().checked_pow(length as u32);
     if !matches!(permutation_count, Some(0..=PERMUTATION_COUNT_MAX)) {
         return Err(NameGenerationError::TooManyPermutations {
-            char_set: chars,
+            symbol_count: symbols.len(),
             length,
         });
     }
 
     // generate all possible names
     trace!("Generating all possible permutations.");
-    let mut candidates = iter::repeat(chars.get_char_set())
-        .take(length)
+    let mut candidates = iter::repeat_n(symbols, length)
         .multi_cartesian_product()
-        .map(|char_seq| char_seq.into_iter().cloned().collect::<String>())
+        .map(|symbol_seq| symbol_seq.into_iter().join(SYMBOL_DELIMITER))
         .collect::<Vec<_>>();
 
-    let mut rng = rand::thread_rng();
-
     let mut name_map = vec![];
     trace!("Randomly matching files to generated names.");
-    for file in files.iter() {
-        // select random name for each file
-        let name = candidates.swap_remove(rng.gen_range(0..candidates.len()));
-        name_map.push((file.as_ref(), name));
+    for group in groups.iter() {
+        let dir_taken = taken_in_dir(taken, group[0].0);
+
+        // select random name for each group, setting aside those taken in this directory
+        // since they may still be usable in other directories
+        let mut set_aside = vec![];
+        let names = loop {
+            if candidates.is_empty() {
+                return Err(NameGenerationError::NoUnusedName {
+                    path: group[0].0.to_owned(),
+                });
+            }
+            let random_name = candidates.swap_remove(rng.gen_range(0..candidates.len()));
+            match finalise_group(group, &random_name, finaliser, dir_taken) {
+                Ok(names) => break names,
+                Err(GroupConflict::Taken(path, name)) => {
+                    debug!("{name:?} is already taken in the directory of {path:?}. Retrying");
+                    set_aside.push(random_name);
+                }
+                Err(GroupConflict::Collision(err)) => return Err(err),
+            }
+        };
+        candidates.append(&mut set_aside);
+        for (path, name) in names {
+            dir_taken.insert(&name);
+            name_map.push((path, name));
+        }
     }
 
     debug!("Generated {} random names.", name_map.len());
@@ -180,12 +828,98 @@ fn generate_then_match(
     Ok(name_map)
 }
 
+/// Encode the digest of each file as its name.
+/// Grouped files all use the digest of the first file in their group.
+///
+/// Unlike the random strategies, a collision cannot be resolved by regenerating,
+/// so any collision is an error.
+fn generate_from_digests<'a>(
+    groups: &[FileGroup<'a>],
+    chars: &CharSet,
+    length: usize,
+    finaliser: &NameFinaliser,
+    taken: &mut TakenNames,
+) -> Result<Vec<(&'a Path, OsString)>, NameGenerationError> {
+    info!("Deriving names from digests.");
+
+    let mut name_map = vec![];
+    for group in groups.iter() {
+        let encoded = encode_with_char_set(&group[0].1.digest, chars);
+        let random_name = encoded[..length].join(SYMBOL_DELIMITER);
+        let dir_taken = taken_in_dir(taken, group[0].0);
+        let names = finalise_group(group, &random_name, finaliser, dir_taken).map_err(|conflict| {
+            conflict.into_error(|path, name| NameGenerationError::DigestCollision { path, name })
+        })?;
+        for (path, name) in names {
+            dir_taken.insert(&name);
+            name_map.push((path, name));
+        }
+    }
+
+    debug!("Derived {} names from digests.", name_map.len());
+    trace!("Pairs: {name_map:?}");
+    Ok(name_map)
+}
+
+/// Assign each group of files a unique index in random order, zero-padded to each of `widths`.
+///
+/// Every index is used exactly once, so a collision with an existing name is an error.
+fn generate_shuffled<'a>(
+    groups: &[FileGroup<'a>],
+    widths: &[usize],
+    rng: &mut impl Rng,
+    finaliser: &NameFinaliser,
+    taken: &mut TakenNames,
+) -> Result<Vec<(&'a Path, OsString)>, NameGenerationError> {
+    info!("Assigning shuffled indices.");
+
+    trace!("Checking if every index fits in the narrowest width.");
+    let min_width = widths.iter().copied().min().unwrap_or_default();
+    let naming_spaces_size = 10usize.saturating_pow(min_width as u32);
+    if groups.len() > naming_spaces_size {
+        return Err(NameGenerationError::InsufficientNamingSpace {
+            needs: groups.len(),
+            space: naming_spaces_size,
+        });
+    }
+
+    let mut indices = (0..groups.len()).collect_vec();
+    indices.shuffle(rng);
+
+    let mut name_map = vec![];
+    for (group, index) in groups.iter().zip(indices) {
+        let random_name = widths
+            .iter()
+            .map(|width| format!("{index:0width$}").chars().join(SYMBOL_DELIMITER))
+            .join(SYMBOL_DELIMITER);
+        let dir_taken = taken_in_dir(taken, group[0].0);
+        let names = finalise_group(group, &random_name, finaliser, dir_taken)
+            .map_err(|conflict| conflict.into_error(|path, name| NameGenerationError::IndexCollision { path, name }))?;
+        for (path, name) in names {
+            dir_taken.insert(&name);
+            name_map.push((path, name));
+        }
+    }
+
+    debug!("Assigned {} shuffled indices.", name_map.len());
+    trace!("Pairs: {name_map:?}");
+    Ok(name_map)
+}
+
 #[derive(Debug)]
 pub enum NameFinaliseError {
-    NotUtf8 { path: PathBuf },
+    IOError(io::Error),
+    /// New names that exceed the maximum name length of their filesystem,
+    /// with the length and the maximum length of each, in the units the filesystem counts.
+    TooLong(Vec<(PathBuf, OsString, usize, usize)>),
     DialoguerError(dialoguer::Error),
     UserHalt,
 }
+impl From<io::Error> for NameFinaliseError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
 impl From<dialoguer::Error> for NameFinaliseError {
     fn from(err: dialoguer::Error) -> Self {
         Self::DialoguerError(err)
@@ -194,7 +928,14 @@ impl From<dialoguer::Error> for NameFinaliseError {
 impl fmt::Display for NameFinaliseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let repr = match self {
-            Self::NotUtf8 { path } => format!("{path:?} is not UTF8"),
+            Self::IOError(err) => err.to_string(),
+            Self::TooLong(names) => {
+                let names = names
+                    .iter()
+                    .map(|(path, name, len, max)| format!("\t{path:?} -> {name:?} ({len} > {max})"))
+                    .join("\n");
+                format!("New names are too long for their filesystem:\n{names}")
+            }
             Self::DialoguerError(err) => err.to_string(),
             Self::UserHalt => "user halt".into(),
         };
@@ -207,158 +948,288 @@ impl From<NameFinaliseError> for String {
     }
 }
 
-/// Append prefix, suffix, and file extension to the new names,
-/// then sanitise the combined names.
+/// The parts of a new name that do not depend on the random characters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameParts {
+    /// The position of the file in the input list.
+    index: usize,
+    /// The position of the first file in the same directory with the same stem, if sidecars are grouped.
+    /// Otherwise, the same as `index`.
+    group: usize,
+    /// The original file name without its extension, if used by the template.
+    stem: OsString,
+    /// The name of the parent directory, if used by the template.
+    parent: OsString,
+    /// The new extension.
+    ext: Option<OsString>,
+    /// The digest of the file, if names are derived from it.
+    digest: Vec<u8>,
+}
+
+/// Gather the parts of each new name required by `template`, other than the random characters.
+/// If `generator` derives names from digests, the digest of each file is computed too.
+/// If `group_sidecars` is set, files in the same directory with the same stem are grouped together.
 ///
 /// The behaviour when an error is encountered depends on `err_mode`.
-pub fn finalise_names<P, S1, S2>(
-    file_random_name_pairs: Vec<(P, String)>,
-    prefix: Option<S1>,
-    suffix: Option<S2>,
-    extension_mode: ExtensionMode,
+pub fn prepare_names<'a, P>(
+    files: &'a [P],
+    template: &NameTemplate,
+    generator: &NameGenerator,
+    extension_mode: &ExtensionMode,
+    group_sidecars: bool,
     err_mode: ErrorHandlingMode,
-) -> Result<Vec<(P, String)>, NameFinaliseError>
+) -> Result<Vec<(&'a Path, NameParts)>, NameFinaliseError>
 where
-    P: AsRef<Path> + fmt::Debug,
-    S1: AsRef<str>,
-    S2: AsRef<str>,
+    P: AsRef<Path>,
 {
-    let mut pairs_with_ext = vec![];
-
-    // append extension
-    if let ExtensionMode::Discard = extension_mode {
-        trace!("No extensions to append.");
-        pairs_with_ext = file_random_name_pairs
-            .into_iter()
-            .map(|(path, random_name)| (path, random_name, None))
-            .collect();
-    } else {
-        debug!("Appending extensions to generated file names.");
-        for (path, random_name) in file_random_name_pairs {
-            let p_ref = path.as_ref();
-            'retry: loop {
-                let ext_res = get_extension(p_ref, &extension_mode);
-                match (ext_res, err_mode) {
-                    (Ok(ext), _) => {
-                        trace!("The new extension for {p_ref:?} is {ext:?}");
-                        pairs_with_ext.push((path, random_name, ext));
-                        break 'retry;
-                    }
-                    (Err(err), ErrorHandlingMode::Ignore) => {
-                        debug!("Error getting extension of {p_ref:?}: {err}. Ignoring.");
-                        break 'retry;
-                    }
-                    (Err(err), ErrorHandlingMode::Warn) => {
-                        debug!("Error getting extension of {p_ref:?}: {err}. Prompting.");
-                        println!("Error getting extension of {}: {err}", format!("{p_ref:?}").red());
-                        let user_response = error_prompt("What to do with this file?", Some(OnErrorResponse::Skip))?;
-                        trace!("User selected \"{user_response}\"");
-
-                        match user_response {
-                            OnErrorResponse::Skip => break 'retry,
-                            OnErrorResponse::Retry => continue 'retry,
-                            OnErrorResponse::Halt => Err(NameFinaliseError::UserHalt)?,
-                        }
+    let placeholders = template.placeholders().collect_vec();
+    let needs_stem = placeholders.contains(&&Placeholder::Stem);
+    let needs_parent = placeholders.contains(&&Placeholder::Parent);
+    let needs_ext = placeholders.contains(&&Placeholder::Ext);
+    let digest_source = match generator {
+        NameGenerator::Digest { source, .. } => Some(source),
+        _ => None,
+    };
+
+    let get_parts = |path: &Path, index: usize| -> Result<NameParts, NameFinaliseError> {
+        Ok(NameParts {
+            index,
+            group: index,
+            stem: if needs_stem {
+                get_stem(path, extension_mode)
+            } else {
+                OsString::new()
+            },
+            parent: if needs_parent {
+                get_parent_name(path).to_owned()
+            } else {
+                OsString::new()
+            },
+            ext: needs_ext.then(|| get_extension(path, extension_mode)).flatten(),
+            digest: digest_source
+                .map(|source| source.digest(path))
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    };
+
+    debug!("Gathering name parts for template \"{template}\".");
+    let mut prepared = vec![];
+    let mut sidecar_groups = HashMap::new();
+    for (index, path) in files.iter().enumerate() {
+        let path = path.as_ref();
+        'retry: loop {
+            let parts_res = get_parts(path, index);
+            match (parts_res, err_mode) {
+                (Ok(mut parts), _) => {
+                    if group_sidecars {
+                        let key = (path.parent(), get_stem(path, extension_mode));
+                        parts.group = *sidecar_groups.entry(key).or_insert(index);
                     }
-                    (Err(err), ErrorHandlingMode::Halt) => {
-                        debug!("Error getting extension of {p_ref:?}: {err}. Halting.");
-                        Err(err)?;
+                    trace!("The name parts for {path:?} are {parts:?}");
+                    prepared.push((path, parts));
+                    break 'retry;
+                }
+                (Err(err), ErrorHandlingMode::Ignore) => {
+                    debug!("Error getting name parts of {path:?}: {err}. Ignoring.");
+                    break 'retry;
+                }
+                (Err(err), ErrorHandlingMode::Warn) => {
+                    debug!("Error getting name parts of {path:?}: {err}. Prompting.");
+                    message!("Error getting name parts of {}: {err}", format!("{path:?}").red());
+                    let user_response = error_prompt("What to do with this file?", Some(OnErrorResponse::Skip))?;
+                    trace!("User selected \"{user_response}\"");
+
+                    match user_response {
+                        OnErrorResponse::Skip => break 'retry,
+                        OnErrorResponse::Retry => continue 'retry,
+                        OnErrorResponse::Halt => Err(NameFinaliseError::UserHalt)?,
                     }
                 }
+                (Err(err), ErrorHandlingMode::Halt) => {
+                    debug!("Error getting name parts of {path:?}: {err}. Halting.");
+                    Err(err)?;
+                }
             }
         }
     }
 
-    // append prefix
-    if let Some(prefix_str) = prefix {
-        debug!("Appending prefix to generated file names.");
-        let p = prefix_str.as_ref();
-        pairs_with_ext
-            .iter_mut()
-            .for_each(|(_, name, _)| *name = format!("{p}{name}"));
-    } else {
-        trace!("No prefix to append.")
-    }
+    debug!("Prepared name parts for {} files.", prepared.len());
+    Ok(prepared)
+}
 
-    // append suffix
-    if let Some(suffix_str) = suffix {
-        debug!("Appending suffix to generated file names.");
-        pairs_with_ext
-            .iter_mut()
-            .for_each(|(_, name, _)| name.push_str(suffix_str.as_ref()));
-    } else {
-        trace!("No suffix to append.")
+/// Combines random characters with the other parts of a name according to a template,
+/// then sanitises the result.
+#[derive(Debug, Clone)]
+pub struct NameFinaliser<'a> {
+    template: &'a NameTemplate,
+    /// The number of random characters (or words) used by each `{rand}` placeholder
+    /// without an explicit length.
+    default_length: usize,
+    /// The separator between words, if the random names are made of words.
+    word_separator: Option<&'a str>,
+    prefix: &'a str,
+    suffix: &'a str,
+    /// The platforms the names must be valid on, in addition to this one.
+    portable: Option<Portability>,
+    date: DateTime<Local>,
+}
+impl<'a> NameFinaliser<'a> {
+    pub fn new(
+        template: &'a NameTemplate,
+        default_length: usize,
+        word_separator: Option<&'a str>,
+        prefix: Option<&'a str>,
+        suffix: Option<&'a str>,
+        portable: Option<Portability>,
+    ) -> Self {
+        Self {
+            template,
+            default_length,
+            word_separator,
+            prefix: prefix.unwrap_or_default(),
+            suffix: suffix.unwrap_or_default(),
+            portable,
+            date: Local::now(),
+        }
     }
 
-    // combine and sanitise
-    debug!("Combining and sanitising file names.");
-    let finalised_pairs = pairs_with_ext
-        .into_iter()
-        .map(|(path, name, ext)| {
-            let name_combined = if let Some(ext) = ext {
-                format!("{name}.{ext}")
-            } else {
-                name
-            };
+    /// Render and sanitise a single name.
+    ///
+    /// The random characters (or words) in `random_name` are separated by `SYMBOL_DELIMITER`,
+    /// and are consumed sequentially by each `{rand}` placeholder.
+    pub fn finalise(&self, parts: &NameParts, random_name: &str) -> OsString {
+        let mut random_symbols = random_name.split(SYMBOL_DELIMITER);
+        let mut name = OsString::new();
 
-            use sanitize_filename as sf;
-            let name_sanitised = sf::sanitize_with_options(
-                name_combined,
-                sf::Options {
-                    // if filename is too long, let `fs::rename` handle it
-                    // this way we fail loudly instead of silently, inadvertently truncating
-                    // the extension or something
-                    truncate: false,
-                    ..Default::default()
+        for part in self.template.parts() {
+            let placeholder = match part {
+                TemplatePart::Literal(literal) => {
+                    name.push(literal);
+                    continue;
+                }
+                TemplatePart::Placeholder(placeholder) => placeholder,
+            };
+            match placeholder {
+                Placeholder::Random(len) => {
+                    let len = len.unwrap_or(self.default_length);
+                    let separator = self.word_separator.unwrap_or_default();
+                    name.push(random_symbols.by_ref().take(len).join(separator));
+                }
+                Placeholder::Stem => name.push(&parts.stem),
+                Placeholder::Parent => name.push(&parts.parent),
+                Placeholder::Index { width } => name.push(format!("{:0width$}", parts.index)),
+                Placeholder::Date(format) => name.push(self.date.format(format).to_string()),
+                Placeholder::Ext => match &parts.ext {
+                    Some(ext) => name.push(ext),
+                    // drop the dot in front of a non-existent extension
+                    None => {
+                        if let Some(stripped) = name.as_encoded_bytes().strip_suffix(b".") {
+                            name = split_os_str_at(&name, stripped.len()).0.to_owned();
+                        }
+                    }
                 },
-            );
+                Placeholder::Prefix => name.push(self.prefix),
+                Placeholder::Suffix => name.push(self.suffix),
+            }
+        }
 
-            (path, name_sanitised)
-        })
-        .collect_vec();
+        // if filename is too long, let `check_name_lengths` report it
+        match (name.into_string(), self.portable) {
+            (Ok(name), None) => sanitise_name(&name).into(),
+            (Ok(name), Some(portability)) => portability.sanitise(&sanitise_name(&name)).into(),
+            // bytes that are not valid UTF-8 are never portable, so they are replaced
+            (Err(name), Some(portability)) => portability.sanitise(&sanitise_name(&name.to_string_lossy())).into(),
+            (Err(name), None) => sanitise_os_name(&name),
+        }
+    }
+}
+
+/// Get the original file name without the extension that would be kept by `ext_mode`.
+///
+/// For modes that do not keep the original extension, the last extension is removed.
+fn get_stem(path: impl AsRef<Path>, ext_mode: &ExtensionMode) -> OsString {
+    let path = path.as_ref();
+    let name = path.file_name().expect("paths should already be canonicalised");
 
-    debug!("Finalised names for {} files.", finalised_pairs.len());
-    trace!("Pairs: {finalised_pairs:?}");
-    Ok(finalised_pairs)
+    let ext = match ext_mode {
+        ExtensionMode::KeepAll | ExtensionMode::KnownCompound(_) => get_extension(path, ext_mode),
+        _ => get_extension(path, &ExtensionMode::KeepLast),
+    };
+    let stem_len = name.len() - ext.map_or(0, |ext| ext.len() + 1);
+    split_os_str_at(name, stem_len).0.to_owned()
 }
 
-fn get_extension(path: impl AsRef<Path>, ext_mode: &ExtensionMode) -> Result<Option<String>, NameFinaliseError> {
+/// Get the name of the directory containing the file.
+fn get_parent_name(path: &Path) -> &OsStr {
+    path.parent().and_then(|parent| parent.file_name()).unwrap_or_default()
+}
+
+fn get_extension(path: impl AsRef<Path>, ext_mode: &ExtensionMode) -> Option<OsString> {
     match ext_mode {
         ExtensionMode::KeepAll => {
             // TODO: see `Errata.md`
-            path.as_ref()
+            let name = path
+                .as_ref()
                 .file_name()
-                .expect("paths should already be canonicalised")
-                .to_str()
-                .ok_or_else(|| NameFinaliseError::NotUtf8 {
-                    path: path.as_ref().to_owned(),
-                })
-                .map(|mut name| {
-                    // currently, the rules are:
-                    // - `None`, if there is no file name;
-                    // - `None`, if there is no embedded `.`;
-                    // - `None`, if the file name begins with `.` and has no other `.`s within;
-                    // - Otherwise, the portion of the file name starting with the first non-beginning `.`
-                    if name.starts_with('.') {
-                        name = &name[1..];
+                .expect("paths should already be canonicalised");
+            // currently, the rules are:
+            // - `None`, if there is no file name;
+            // - `None`, if there is no embedded `.`;
+            // - `None`, if the file name begins with `.` and has no other `.`s within;
+            // - Otherwise, the portion of the file name starting with the first non-beginning `.`
+            let bytes = name.as_encoded_bytes();
+            let start = usize::from(bytes.starts_with(b"."));
+            bytes[start..]
+                .iter()
+                .position(|&byte| byte == b'.')
+                .map(|idx| split_os_str_at(name, start + idx + 1).1.to_owned())
+        }
+        ExtensionMode::KeepLast => path.as_ref().extension().map(OsStr::to_owned),
+        ExtensionMode::KnownCompound(exts) => {
+            let name = path
+                .as_ref()
+                .file_name()
+                .expect("paths should already be canonicalised");
+            let bytes = name.as_encoded_bytes();
+            // the longest match wins, as long as something is left of the stem
+            let compound_len = exts
+                .iter()
+                .filter(|ext| {
+                    bytes.len() > ext.len() + 1 && {
+                        let tail = &bytes[bytes.len() - ext.len() - 1..];
+                        tail[0] == b'.' && tail[1..].eq_ignore_ascii_case(ext.as_bytes())
                     }
-                    name.split_once('.').map(|(_, after)| after.to_owned())
                 })
+                .map(String::len)
+                .max();
+            match compound_len {
+                Some(len) => Some(split_os_str_at(name, bytes.len() - len).1.to_owned()),
+                None => path.as_ref().extension().map(OsStr::to_owned),
+            }
         }
-        ExtensionMode::KeepLast => path
-            .as_ref()
-            .extension()
-            .map(|ext| {
-                ext.to_str()
-                    .map(|s| s.to_owned())
-                    .ok_or_else(|| NameFinaliseError::NotUtf8 {
-                        path: path.as_ref().to_owned(),
-                    })
-            })
-            .transpose(),
-        ExtensionMode::Static(ext) => Ok(Some(ext.clone())),
+        ExtensionMode::Static(ext) => Some(ext.into()),
         // this case should be unreachable because we already guard against it
         // but impl is trivial so it's here anyway
-        ExtensionMode::Discard => Ok(None),
+        ExtensionMode::Discard => None,
+    }
+}
+
+/// Split a name at byte `idx` of its encoded form.
+///
+/// `idx` must be at either end of the name, or next to an ASCII character (e.g. the `.` before an extension).
+fn split_os_str_at(name: &OsStr, idx: usize) -> (&OsStr, &OsStr) {
+    let (head, tail) = name.as_encoded_bytes().split_at(idx);
+    assert!(
+        head.last().is_none_or(u8::is_ascii) || tail.first().is_none_or(u8::is_ascii),
+        "{name:?} should only be split next to an ASCII character"
+    );
+    // SAFETY: an ASCII byte is always a whole character in the encoded form,
+    // so both halves are split on a valid boundary
+    unsafe {
+        (
+            OsStr::from_encoded_bytes_unchecked(head),
+            OsStr::from_encoded_bytes_unchecked(tail),
+        )
     }
 }
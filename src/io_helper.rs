@@ -1,19 +1,220 @@
 use std::{
-    fmt, fs, io,
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    io::{self, Read},
     path::{Path, PathBuf},
+    process,
     str::FromStr,
 };
 
+use clap::crate_name;
 use dialoguer::Input;
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use itertools::Itertools;
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use walkdir::WalkDir;
 use yansi::Paint;
 
 use crate::{
-    cli::{ConfirmMode, ErrorHandlingMode},
-    util::{error_prompt, OnErrorResponse},
+    cli::{CaseInsensitivity, ConfirmMode, ErrorHandlingMode, Portability},
+    compute::{fold_name, DirNames, NameFinaliseError, TakenNames},
+    journal::Journal,
+    portable::PORTABLE_NAME_MAX,
+    util::{error_prompt, message, OnErrorResponse},
 };
 
+/// Read a list of paths from `source`, or from stdin if `source` is `-`.
+///
+/// Paths are separated by NUL if `null_separated` is set, or by newlines otherwise.
+/// Empty entries are ignored.
+pub fn read_file_list(source: impl AsRef<Path>, null_separated: bool) -> io::Result<Vec<PathBuf>> {
+    let source = source.as_ref();
+    let mut buf = vec![];
+    if source == Path::new("-") {
+        debug!("Reading file list from stdin.");
+        io::stdin().lock().read_to_end(&mut buf)?;
+    } else {
+        debug!("Reading file list from {source:?}.");
+        buf = fs::read(source)?;
+    }
+
+    let separator = if null_separated { b'\0' } else { b'\n' };
+    let paths = buf
+        .split(|&b| b == separator)
+        .map(|entry| {
+            // tolerate CRLF line endings
+            match entry {
+                [rest @ .., b'\r'] if !null_separated => rest,
+                _ => entry,
+            }
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(bytes_to_path)
+        .collect::<io::Result<Vec<_>>>()?;
+
+    debug!("Read {} paths from file list.", paths.len());
+    Ok(paths)
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: &[u8]) -> io::Result<PathBuf> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    Ok(OsStr::from_bytes(bytes).into())
+}
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: &[u8]) -> io::Result<PathBuf> {
+    std::str::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[derive(Debug)]
+pub enum ExpandError {
+    WalkError(walkdir::Error),
+    GlobError(globset::Error),
+    DialoguerError(dialoguer::Error),
+    UserHalt,
+}
+impl From<walkdir::Error> for ExpandError {
+    fn from(err: walkdir::Error) -> Self {
+        Self::WalkError(err)
+    }
+}
+impl From<globset::Error> for ExpandError {
+    fn from(err: globset::Error) -> Self {
+        Self::GlobError(err)
+    }
+}
+impl From<dialoguer::Error> for ExpandError {
+    fn from(err: dialoguer::Error) -> Self {
+        Self::DialoguerError(err)
+    }
+}
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Self::WalkError(err) => err.to_string(),
+            Self::GlobError(err) => err.to_string(),
+            Self::DialoguerError(err) => err.to_string(),
+            Self::UserHalt => "user halt".into(),
+        };
+        write!(f, "Failed during directory traversal step: {repr}")
+    }
+}
+impl From<ExpandError> for String {
+    fn from(err: ExpandError) -> Self {
+        err.to_string()
+    }
+}
+
+/// Recursively expand all directories into the files beneath them,
+/// keeping only files matching `include` (if specified) and not matching `exclude`.
+///
+/// Patterns are matched against the path relative to the directory being expanded,
+/// or the file name for files that are passed directly.
+///
+/// Directory entries are visited in file name order so that the output is deterministic.
+/// Symlinks found within directories are skipped.
+///
+/// The behaviour when an error is encountered depends on `err_mode`.
+pub fn expand_paths<P>(
+    files: &[P],
+    include: &[Glob],
+    exclude: &[Glob],
+    max_depth: Option<usize>,
+    err_mode: ErrorHandlingMode,
+) -> Result<Vec<PathBuf>, ExpandError>
+where
+    P: AsRef<Path>,
+{
+    fn build_set(globs: &[Glob]) -> Result<GlobSet, globset::Error> {
+        globs
+            .iter()
+            .fold(&mut GlobSetBuilder::new(), |builder, glob| builder.add(glob.clone()))
+            .build()
+    }
+    let include_set = (!include.is_empty()).then(|| build_set(include)).transpose()?;
+    let exclude_set = build_set(exclude)?;
+    let is_wanted = |rel_path: &Path| {
+        include_set.as_ref().is_none_or(|set| set.is_match(rel_path)) && !exclude_set.is_match(rel_path)
+    };
+
+    let mut expanded = vec![];
+
+    for path in files {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            // let canonicalisation report any errors with this path
+            let name = path.file_name().map_or(path, Path::new);
+            if is_wanted(name) {
+                expanded.push(path.to_owned());
+            } else {
+                trace!("Excluding {path:?} by filter.");
+            }
+            continue;
+        }
+
+        'retry: loop {
+            let mut walker = WalkDir::new(path).min_depth(1).sort_by_file_name();
+            if let Some(depth) = max_depth {
+                walker = walker.max_depth(depth);
+            }
+
+            let mut dir_files = vec![];
+            for entry_res in walker {
+                match (entry_res, err_mode) {
+                    (Ok(entry), _) => {
+                        if entry.file_type().is_dir() {
+                            continue;
+                        }
+                        // symlinks are resolved when canonicalising, which may lead outside the directory
+                        if entry.path_is_symlink() {
+                            trace!("Skipping symlink {:?}.", entry.path());
+                            continue;
+                        }
+                        let rel_path = entry
+                            .path()
+                            .strip_prefix(path)
+                            .expect("walked entries should be under the walked directory");
+                        if is_wanted(rel_path) {
+                            dir_files.push(entry.into_path());
+                        } else {
+                            trace!("Excluding {:?} by filter.", entry.path());
+                        }
+                    }
+                    (Err(err), ErrorHandlingMode::Ignore) => {
+                        debug!("Error traversing directory {path:?}: {err}. Ignoring.");
+                    }
+                    (Err(err), ErrorHandlingMode::Warn) => {
+                        debug!("Error traversing directory {path:?}: {err}. Prompting.");
+
+                        message!("Error traversing directory {}: {err}", format!("{path:?}").red());
+                        let user_response = error_prompt("What to do with this entry?", Some(OnErrorResponse::Skip))?;
+                        trace!("User selected \"{user_response}\"");
+
+                        match user_response {
+                            OnErrorResponse::Skip => {}
+                            OnErrorResponse::Retry => continue 'retry,
+                            OnErrorResponse::Halt => Err(ExpandError::UserHalt)?,
+                        }
+                    }
+                    (Err(err), ErrorHandlingMode::Halt) => {
+                        debug!("Error traversing directory {path:?}: {err}. Failing.");
+                        Err(err)?;
+                    }
+                }
+            }
+
+            debug!("Expanded directory {path:?} into {} files.", dir_files.len());
+            expanded.append(&mut dir_files);
+            break 'retry;
+        }
+    }
+
+    Ok(expanded)
+}
+
 #[derive(Debug)]
 pub enum DedupError {
     IOError(io::Error),
@@ -46,7 +247,7 @@ impl From<DedupError> for String {
     }
 }
 
-/// Canonicalise all paths, then deduplicate them.
+/// Canonicalise all paths, then deduplicate them, keeping the first occurrence of each.
 ///
 /// The behaviour when an error is encountered depends on `err_mode`.
 pub fn dedup_paths<P>(files: &[P], err_mode: ErrorHandlingMode) -> Result<Vec<PathBuf>, DedupError>
@@ -72,7 +273,7 @@ where
                 (Err(err), ErrorHandlingMode::Warn) => {
                     debug!("Error canonicalising path {path:?}: {err}. Prompting.");
 
-                    println!("Error canonicalising path {}: {err}", format!("{path:?}").red());
+                    message!("Error canonicalising path {}: {err}", format!("{path:?}").red());
                     let user_response = error_prompt("What to do with this path?", Some(OnErrorResponse::Skip))?;
                     trace!("User selected \"{user_response}\"");
 
@@ -90,13 +291,110 @@ where
         }
     }
 
-    canonicalised.dedup();
-    Ok(canonicalised)
+    // the same file may be listed far apart, e.g. by several sources
+    Ok(canonicalised.into_iter().unique().collect())
+}
+
+/// The directories in which names that differ only in case refer to the same file.
+pub type CaseInsensitiveDirs = HashSet<PathBuf>;
+
+/// Get the directories in `taken` that are case-insensitive.
+pub fn case_insensitive_dirs(taken: &TakenNames) -> CaseInsensitiveDirs {
+    taken
+        .iter()
+        .filter(|(_, names)| names.is_case_insensitive())
+        .map(|(dir, _)| dir.clone())
+        .collect()
+}
+
+/// The form of `path` its directory considers unique,
+/// i.e. with the file name case folded if the directory is one of `ci_dirs`.
+pub fn path_key(path: &Path, ci_dirs: &CaseInsensitiveDirs) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) if ci_dirs.contains(dir) => dir.join(fold_name(name)),
+        _ => path.to_owned(),
+    }
+}
+
+/// List the names of all entries in each of `dirs`, and whether each directory is case-insensitive.
+pub fn list_dir_names<P>(
+    dirs: impl IntoIterator<Item = P>,
+    case_insensitivity: CaseInsensitivity,
+) -> io::Result<TakenNames>
+where
+    P: AsRef<Path>,
+{
+    let mut taken = TakenNames::new();
+    for dir in dirs {
+        let dir = dir.as_ref();
+        if taken.contains_key(dir) {
+            continue;
+        }
+        let names = fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<io::Result<HashSet<_>>>()?;
+        trace!("Found {} existing names in {dir:?}.", names.len());
+        let case_insensitive = match case_insensitivity {
+            CaseInsensitivity::Auto => detect_case_insensitive(dir, &names),
+            CaseInsensitivity::Always => true,
+            CaseInsensitivity::Never => false,
+        };
+        taken.insert(dir.to_owned(), DirNames::new(names, case_insensitive));
+    }
+    Ok(taken)
+}
+
+/// Detect whether `dir` is case-insensitive, by looking up one of its `names` with the case flipped.
+///
+/// If the flipped name is not listed in the directory but can still be found,
+/// it must be referring to the original entry. If no name contains ASCII letters,
+/// the directory is assumed to be case-sensitive.
+///
+/// Only ASCII letters are flipped, since other characters may not map back to themselves (e.g. `ß` to `SS`),
+/// and some filesystems (e.g. vfat) ignore the case of ASCII letters only.
+/// The names are probed in sorted order, so that the result does not change between runs.
+fn detect_case_insensitive(dir: &Path, names: &HashSet<OsString>) -> bool {
+    let flip_ascii_case = |name: &str| {
+        name.chars()
+            .map(|c| match c {
+                'a'..='z' => c.to_ascii_uppercase(),
+                'A'..='Z' => c.to_ascii_lowercase(),
+                _ => c,
+            })
+            .collect::<String>()
+    };
+    let probe = names
+        .iter()
+        .filter_map(|name| name.to_str())
+        .filter(|name| name.bytes().any(|b| b.is_ascii_alphabetic()))
+        .sorted()
+        .map(|name| (name, flip_ascii_case(name)))
+        .find(|(_, flipped)| !names.contains(OsStr::new(flipped)));
+    match probe {
+        Some((name, flipped)) => {
+            let case_insensitive = dir.join(&flipped).symlink_metadata().is_ok();
+            debug!("Probed {name:?} as {flipped:?}; {dir:?} is case-insensitive: {case_insensitive}.");
+            case_insensitive
+        }
+        None => {
+            debug!("Cannot probe the case sensitivity of {dir:?}; assuming case-sensitive.");
+            false
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum RenameError {
     IOError(io::Error),
+    AlreadyExists {
+        path: PathBuf,
+        new_abs_path: PathBuf,
+    },
+    RolledBack(Box<RenameError>),
+    RollbackFailed {
+        cause: Box<RenameError>,
+        failures: Vec<(PathBuf, PathBuf, io::Error)>,
+    },
     DialoguerError(dialoguer::Error),
     UserHalt,
 }
@@ -112,12 +410,37 @@ impl From<dialoguer::Error> for RenameError {
 }
 impl fmt::Display for RenameError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let repr = match self {
+        write!(f, "Failed during rename step: {}", self.reason())
+    }
+}
+impl RenameError {
+    /// The description of the error, without the context prefix.
+    pub fn reason(&self) -> String {
+        match self {
             Self::IOError(err) => err.to_string(),
+            Self::AlreadyExists { path, new_abs_path } => {
+                format!("renaming {path:?} to {new_abs_path:?} will overwrite an existing file")
+            }
+            Self::RolledBack(cause) => {
+                format!(
+                    "{}; all renames performed in this run have been rolled back",
+                    cause.reason()
+                )
+            }
+            Self::RollbackFailed { cause, failures } => {
+                let failures_text = failures
+                    .iter()
+                    .map(|(from, to, err)| format!("\t{from:?} -> {to:?}: {err}"))
+                    .join("\n");
+                format!(
+                    "{}; failed to roll back {} renames:\n{failures_text}",
+                    cause.reason(),
+                    failures.len()
+                )
+            }
             Self::DialoguerError(err) => err.to_string(),
             Self::UserHalt => "user halt".into(),
-        };
-        write!(f, "Failed during rename step: {repr}")
+        }
     }
 }
 impl From<RenameError> for String {
@@ -126,75 +449,428 @@ impl From<RenameError> for String {
     }
 }
 
+/// The outcome of renaming a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenameStatus {
+    /// The rename was never attempted, e.g. because the run was halted.
+    Pending,
+    /// The file was renamed.
+    Renamed,
+    /// The rename was only previewed in dry run mode.
+    Previewed,
+    /// The user skipped the batch containing this file.
+    Skipped,
+    /// The rename failed with an error.
+    Failed(String),
+    /// The file was renamed, but then reverted because the run failed in atomic mode.
+    RolledBack,
+}
+impl fmt::Display for RenameStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Self::Pending => "pending",
+            Self::Renamed => "renamed",
+            Self::Previewed => "previewed",
+            Self::Skipped => "skipped",
+            Self::Failed(_) => "failed",
+            Self::RolledBack => "rolled-back",
+        };
+        write!(f, "{repr}")
+    }
+}
+
+/// How `rename_files` performs the renames.
+#[derive(Debug, Copy, Clone)]
+pub struct RenameOptions {
+    pub dry_run: bool,
+    pub confirm_mode: ConfirmMode,
+    /// The number of files to confirm at a time when `confirm_mode` is `batch`. 0 = unlimited.
+    pub confirm_batch_size: usize,
+    pub err_mode: ErrorHandlingMode,
+    pub atomic: bool,
+}
+
 /// Perform the rename using the provided `path`-`new name` pairs.
 /// Returns the number of successfully renamed files.
 ///
-/// The behaviour when an error is encountered depends on `err_mode`.
+/// The renames are reordered so that files whose names are to be reused by other files
+/// in the same run are renamed first. If several files swap names in a cycle, one of them
+/// is temporarily moved out of the way.
+///
+/// Every successful rename (including temporary ones) is recorded in `journal`, if provided.
+/// The outcome of each rename is written to the corresponding element of `statuses`,
+/// which should be the same length as `pairs_list`.
+///
+/// Paths in `ci_dirs` are compared ignoring case, so that a new name differing from a current name
+/// only in case is treated as the same file.
+///
+/// The behaviour when an error is encountered depends on `options.err_mode`.
+/// If `options.atomic` is set, any failure or halt reverts every rename already performed in this run,
+/// so that either all files are renamed or none are.
 pub fn rename_files<P, S>(
     pairs_list: &[(P, S)],
-    dry_run: bool,
-    confirm_mode: ConfirmMode,
-    confirm_batch_size: usize,
-    err_mode: ErrorHandlingMode,
+    ci_dirs: &CaseInsensitiveDirs,
+    options: RenameOptions,
+    journal: Option<&mut Journal>,
+    statuses: &mut [RenameStatus],
 ) -> Result<usize, RenameError>
 where
     P: AsRef<Path>,
-    S: AsRef<str>,
+    S: AsRef<OsStr>,
 {
-    match confirm_mode {
-        ConfirmMode::None => rename_files_no_confirm(pairs_list, dry_run, err_mode),
-        ConfirmMode::Batch => rename_files_confirm(pairs_list, dry_run, confirm_batch_size, err_mode),
-        ConfirmMode::Each => rename_files_confirm(pairs_list, dry_run, 1, err_mode),
+    assert_eq!(pairs_list.len(), statuses.len(), "there should be one status per pair");
+
+    let units = plan_renames(pairs_list, ci_dirs);
+    let RenameOptions {
+        dry_run,
+        confirm_mode,
+        confirm_batch_size,
+        err_mode,
+        atomic,
+    } = options;
+    let mut run = RenameRun::new(pairs_list, ci_dirs, dry_run, err_mode, atomic, journal);
+    let res = match confirm_mode {
+        ConfirmMode::None => rename_files_no_confirm(pairs_list, &units.concat(), &mut run, statuses),
+        ConfirmMode::Batch => rename_files_confirm(pairs_list, &units, &mut run, confirm_batch_size, statuses),
+        ConfirmMode::Each => rename_files_confirm(pairs_list, &units, &mut run, 1, statuses),
+    };
+    if !(atomic && res.is_err()) {
+        run.restore_stranded(pairs_list, statuses);
+    }
+
+    match res {
+        Err(err) if atomic && !dry_run => {
+            let failures = run.rollback(statuses);
+            if failures.is_empty() {
+                Err(RenameError::RolledBack(Box::new(err)))
+            } else {
+                Err(RenameError::RollbackFailed {
+                    cause: Box::new(err),
+                    failures,
+                })
+            }
+        }
+        res => res,
     }
 }
 
-fn rename_files_no_confirm(
-    pairs_list: &[(impl AsRef<Path>, impl AsRef<str>)],
+/// Determine the order in which to perform the renames, such that whenever possible,
+/// a file is renamed only after the file currently holding its new name has moved away.
+///
+/// Returns the indices of `pairs_list` in the planned order, split into units that
+/// must be confirmed together. Each rename cycle is a single unit, since renaming only part of it
+/// would leave a file stranded under its temporary name.
+fn plan_renames(
+    pairs_list: &[(impl AsRef<Path>, impl AsRef<OsStr>)],
+    ci_dirs: &CaseInsensitiveDirs,
+) -> Vec<Vec<usize>> {
+    // since each path is unique, each rename is blocked by at most one other rename
+    let src_indices: HashMap<PathBuf, usize> = pairs_list
+        .iter()
+        .enumerate()
+        .map(|(idx, (path, _))| (path_key(path.as_ref(), ci_dirs), idx))
+        .collect();
+    let blockers = pairs_list
+        .iter()
+        .enumerate()
+        .map(|(idx, (path, new_name))| {
+            let target = new_path_of(path.as_ref(), new_name.as_ref());
+            src_indices
+                .get(&path_key(&target, ci_dirs))
+                .copied()
+                .filter(|&blocker| blocker != idx)
+        })
+        .collect_vec();
+
+    let mut units = vec![];
+    let mut visited = vec![false; pairs_list.len()];
+    for start in 0..pairs_list.len() {
+        // follow the chain of blockers until it ends, or loops back onto itself
+        let mut chain = vec![];
+        let mut next = Some(start);
+        while let Some(idx) = next.filter(|&idx| !visited[idx]) {
+            visited[idx] = true;
+            chain.push(idx);
+            next = blockers[idx];
+        }
+        // the chain may end in a cycle back onto one of its own members
+        let cycle_start = next.and_then(|idx| chain.iter().position(|&member| member == idx));
+        let cycle = cycle_start.map(|pos| chain.split_off(pos));
+        // blockers go first
+        units.extend(cycle.map(|cycle| cycle.into_iter().rev().collect()));
+        units.extend(chain.into_iter().rev().map(|idx| vec![idx]));
+    }
+
+    trace!("Planned rename order: {units:?}");
+    units
+}
+
+/// State shared by every rename in a single run.
+struct RenameRun<'j> {
+    ci_dirs: &'j CaseInsensitiveDirs,
     dry_run: bool,
     err_mode: ErrorHandlingMode,
-) -> Result<usize, RenameError> {
-    let mut success_count = 0;
+    atomic: bool,
+    journal: Option<&'j mut Journal>,
+    /// Every rename actually performed so far (including temporary ones),
+    /// as the index of the file and its old and new paths.
+    performed: Vec<(usize, PathBuf, PathBuf)>,
+    /// The current path of each file, which changes if it is temporarily moved out of the way.
+    current_paths: Vec<PathBuf>,
+    /// The files that have not been renamed yet, keyed by `path_key` of their current path.
+    pending: HashMap<PathBuf, usize>,
+    /// The `path_key`s of the paths that would have been moved away, in a dry run.
+    vacated: HashSet<PathBuf>,
+    /// The number of temporary names created so far.
+    temp_count: usize,
+}
+impl<'j> RenameRun<'j> {
+    fn new(
+        pairs_list: &[(impl AsRef<Path>, impl AsRef<OsStr>)],
+        ci_dirs: &'j CaseInsensitiveDirs,
+        dry_run: bool,
+        err_mode: ErrorHandlingMode,
+        atomic: bool,
+        journal: Option<&'j mut Journal>,
+    ) -> Self {
+        let current_paths = pairs_list
+            .iter()
+            .map(|(path, _)| path.as_ref().to_owned())
+            .collect_vec();
+        let pending = current_paths
+            .iter()
+            .enumerate()
+            .map(|(idx, p)| (path_key(p, ci_dirs), idx))
+            .collect();
+        Self {
+            ci_dirs,
+            dry_run,
+            err_mode,
+            atomic,
+            journal,
+            performed: vec![],
+            current_paths,
+            pending,
+            vacated: HashSet::new(),
+            temp_count: 0,
+        }
+    }
 
-    debug!("Renaming files without confirmation.");
-    for (path, new_name) in pairs_list {
-        let path = path.as_ref();
-        let new_name = new_name.as_ref();
-        'retry: loop {
-            let rename_res = do_rename(path, new_name, dry_run);
-            match (rename_res, err_mode) {
-                (Ok(_), _) => {
-                    trace!("Rename from {path:?} to {new_name} successful.");
-                    success_count += 1;
-                    break 'retry;
+    /// Rename a single file, recording the outcome in `status`.
+    /// Returns whether the rename was successful.
+    ///
+    /// The behaviour when an error is encountered depends on `err_mode`.
+    fn rename_with_retry(
+        &mut self,
+        idx: usize,
+        new_name: &OsStr,
+        status: &mut RenameStatus,
+    ) -> Result<bool, RenameError> {
+        // once attempted, a file is no longer moved out of the way, even if its rename fails
+        let path = self.current_paths[idx].clone();
+        self.pending.remove(&path_key(&path, self.ci_dirs));
+
+        loop {
+            let rename_res = self.rename_one(&path, new_name);
+            if let Err(err) = &rename_res {
+                *status = RenameStatus::Failed(err.reason());
+            }
+            match (rename_res, self.err_mode) {
+                (Ok(new_path), _) => {
+                    trace!("Rename from {path:?} to {new_name:?} successful.");
+                    *status = if self.dry_run {
+                        RenameStatus::Previewed
+                    } else {
+                        // a file that already has its new name has nothing to roll back
+                        if new_path != path {
+                            self.record(idx, &path, &new_path);
+                        }
+                        RenameStatus::Renamed
+                    };
+                    return Ok(true);
+                }
+                (Err(err), ErrorHandlingMode::Ignore) if self.atomic => {
+                    debug!(
+                        "Failed to rename {path:?} to {new_name:?}: {}. Halting to roll back.",
+                        err.reason()
+                    );
+                    Err(err)?;
                 }
                 (Err(err), ErrorHandlingMode::Ignore) => {
-                    debug!("Failed to rename {path:?} to {new_name}: {err}, ignoring.");
-                    break 'retry;
+                    debug!("Failed to rename {path:?} to {new_name:?}: {}, ignoring.", err.reason());
+                    return Ok(false);
                 }
                 (Err(err), ErrorHandlingMode::Warn) => {
-                    debug!("Failed to rename {path:?} to {new_name}: {err}. Prompting.");
-                    println!(
-                        "Failed to rename {:?} to {}: {err}",
+                    debug!(
+                        "Failed to rename {path:?} to {new_name:?}: {}. Prompting.",
+                        err.reason()
+                    );
+                    message!(
+                        "Failed to rename {:?} to {}: {}",
                         format!("{path:?}").red(),
-                        new_name.red(),
+                        new_name.display().red(),
+                        err.reason(),
                     );
                     let user_response = error_prompt("What to do with this file?", Some(OnErrorResponse::Skip))?;
                     trace!("User selected \"{user_response}\"");
 
                     match user_response {
-                        OnErrorResponse::Skip => break 'retry,
-                        OnErrorResponse::Retry => continue 'retry,
+                        // skipping a file would leave the run incomplete
+                        OnErrorResponse::Skip if self.atomic => Err(err)?,
+                        OnErrorResponse::Skip => return Ok(false),
+                        OnErrorResponse::Retry => continue,
                         OnErrorResponse::Halt => Err(RenameError::UserHalt)?,
                     }
                 }
                 (Err(err), ErrorHandlingMode::Halt) => {
-                    debug!("Failed to rename {path:?} to {new_name}: {err}. Halting.");
+                    debug!("Failed to rename {path:?} to {new_name:?}: {}. Halting.", err.reason());
                     Err(err)?;
                 }
             }
         }
     }
 
+    /// Rename a single file, first moving any file in this run that is still
+    /// occupying the new name out of the way.
+    /// Returns the new absolute path.
+    fn rename_one(&mut self, path: &Path, new_name: &OsStr) -> Result<PathBuf, RenameError> {
+        let new_abs_path = new_path_of(path, new_name);
+        if new_abs_path == path {
+            trace!("{path:?} already has the name {new_name:?}.");
+            return Ok(new_abs_path);
+        }
+
+        let new_key = path_key(&new_abs_path, self.ci_dirs);
+        // in a case-insensitive directory, a file can be renamed onto itself to change its case only
+        let is_same_file = new_key == path_key(path, self.ci_dirs);
+        let blocker = self.pending.get(&new_key).copied().filter(|_| !is_same_file);
+        let allow_existing = if self.dry_run {
+            // nothing actually moves in a dry run, so pretend it did
+            is_same_file || blocker.is_some() || self.vacated.contains(&new_key)
+        } else {
+            if let Some(blocker) = blocker {
+                self.move_aside(blocker)?;
+            }
+            is_same_file
+        };
+        do_rename(path, &new_abs_path, self.dry_run, allow_existing)?;
+
+        if self.dry_run {
+            self.vacated.insert(path_key(path, self.ci_dirs));
+        }
+        Ok(new_abs_path)
+    }
+
+    /// Temporarily move a file that has not been renamed yet to a unique name in the same directory.
+    fn move_aside(&mut self, idx: usize) -> Result<(), RenameError> {
+        let path = self.current_paths[idx].clone();
+        let temp_path = loop {
+            self.temp_count += 1;
+            let temp_name = format!(".{}-tmp-{}-{}", crate_name!(), process::id(), self.temp_count);
+            let temp_path = new_path_of(&path, temp_name.as_ref());
+            if !temp_path.try_exists()? {
+                break temp_path;
+            }
+        };
+
+        debug!("Temporarily moving {path:?} to {temp_path:?} to break a rename cycle.");
+        do_rename(&path, &temp_path, false, false)?;
+        self.record(idx, &path, &temp_path);
+
+        self.pending.remove(&path_key(&path, self.ci_dirs));
+        self.pending.insert(path_key(&temp_path, self.ci_dirs), idx);
+        self.current_paths[idx] = temp_path;
+        Ok(())
+    }
+
+    /// Move every file that is still under a temporary name back to its original path,
+    /// e.g. because the rest of its cycle failed or the run was halted.
+    fn restore_stranded(&mut self, pairs_list: &[(impl AsRef<Path>, impl AsRef<OsStr>)], statuses: &[RenameStatus]) {
+        for (idx, (original, _)) in pairs_list.iter().enumerate() {
+            let original = original.as_ref();
+            let current = self.current_paths[idx].clone();
+            if current == original || statuses[idx] == RenameStatus::Renamed {
+                continue;
+            }
+            match rename_no_replace(&current, original) {
+                Ok(()) => {
+                    debug!("Moved {current:?} back to {original:?}.");
+                    self.record(idx, &current, original);
+                    self.current_paths[idx] = original.to_owned();
+                }
+                Err(err) => {
+                    warn!("{original:?} was left at the temporary path {current:?}: {err}");
+                }
+            }
+        }
+    }
+
+    /// Record a rename that was actually performed.
+    fn record(&mut self, idx: usize, from: &Path, to: &Path) {
+        if let Some(journal) = self.journal.as_deref_mut() {
+            journal.record(from, to);
+        }
+        self.performed.push((idx, from.to_owned(), to.to_owned()));
+    }
+
+    /// Revert every rename performed so far, in reverse order, updating `statuses` accordingly.
+    /// The reverting renames are also recorded in the journal.
+    ///
+    /// Returns the renames that could not be reverted.
+    fn rollback(&mut self, statuses: &mut [RenameStatus]) -> Vec<(PathBuf, PathBuf, io::Error)> {
+        info!("Rolling back {} renames.", self.performed.len());
+
+        let mut failures = vec![];
+        let mut failed_indices = HashSet::new();
+        for (idx, from, to) in std::mem::take(&mut self.performed).into_iter().rev() {
+            trace!("Rolling back {to:?} to {from:?}.");
+            // as in `rename_one`, a case-only rename must be reverted onto the same file
+            let is_same_file = path_key(&to, self.ci_dirs) == path_key(&from, self.ci_dirs);
+            let res = if is_same_file {
+                fs::rename(&to, &from)
+            } else {
+                rename_no_replace(&to, &from)
+            };
+            match res {
+                Ok(()) => {
+                    if let Some(journal) = self.journal.as_deref_mut() {
+                        journal.record(&to, &from);
+                    }
+                    self.current_paths[idx] = from;
+                }
+                Err(err) => {
+                    debug!("Failed to roll back {to:?} to {from:?}: {err}.");
+                    failed_indices.insert(idx);
+                    failures.push((to, from, err));
+                }
+            }
+        }
+
+        for (idx, status) in statuses.iter_mut().enumerate() {
+            if *status == RenameStatus::Renamed && !failed_indices.contains(&idx) {
+                *status = RenameStatus::RolledBack;
+            }
+        }
+        failures
+    }
+}
+
+fn rename_files_no_confirm(
+    pairs_list: &[(impl AsRef<Path>, impl AsRef<OsStr>)],
+    order: &[usize],
+    run: &mut RenameRun,
+    statuses: &mut [RenameStatus],
+) -> Result<usize, RenameError> {
+    let mut success_count = 0;
+
+    debug!("Renaming files without confirmation.");
+    for &idx in order {
+        let new_name = pairs_list[idx].1.as_ref();
+        if run.rename_with_retry(idx, new_name, &mut statuses[idx])? {
+            success_count += 1;
+        }
+    }
+
     info!("Successfully renamed {success_count} files");
     Ok(success_count)
 }
@@ -229,24 +905,33 @@ impl fmt::Display for BatchConfirmResponse {
 }
 
 fn rename_files_confirm(
-    pairs_list: &[(impl AsRef<Path>, impl AsRef<str>)],
-    dry_run: bool,
+    pairs_list: &[(impl AsRef<Path>, impl AsRef<OsStr>)],
+    units: &[Vec<usize>],
+    run: &mut RenameRun,
     batch_size: usize,
-    err_mode: ErrorHandlingMode,
+    statuses: &mut [RenameStatus],
 ) -> Result<usize, RenameError> {
     let mut success_count = 0;
 
     debug!("Renaming files with confirmation and batch size of {batch_size}.");
-    let batch_count = ((pairs_list.len() as f64) / (batch_size as f64)).ceil() as usize;
-    'batch: for (batch_idx, batch) in pairs_list.chunks(batch_size).enumerate() {
+    // a unit is never split, so a batch may be larger than `batch_size` to fit it
+    let mut batches: Vec<Vec<usize>> = vec![];
+    for unit in units {
+        match batches.last_mut() {
+            Some(batch) if batch.len() + unit.len() <= batch_size => batch.extend(unit),
+            _ => batches.push(unit.clone()),
+        }
+    }
+    let batch_count = batches.len();
+    'batch: for (batch_idx, batch) in batches.iter().enumerate() {
         trace!("Processing batch {batch_idx}.");
 
         // confirm batch
-        println!(
+        message!(
             "Batch {}/{}{}:",
             format!("#{}", batch_idx + 1).yellow(),
             batch_count.green(),
-            if dry_run {
+            if run.dry_run {
                 format!(" ({})", "DRY RUN".red())
             } else {
                 "".into()
@@ -254,15 +939,16 @@ fn rename_files_confirm(
         );
         let batch_info_text = batch
             .iter()
-            .map(|(path, new_name)| {
+            .map(|&idx| {
+                let (path, new_name) = &pairs_list[idx];
                 format!(
-                    "\t{} -> {}",
+                    "\t{} -> \"{}\"",
                     format!("{:?}", path.as_ref()).yellow(),
-                    format!("\"{}\"", new_name.as_ref().green())
+                    new_name.as_ref().display().green()
                 )
             })
             .join("\n");
-        println!("{batch_info_text}");
+        message!("{batch_info_text}");
 
         let prompt_text = format!(
             "Confirm batch? You can {}({}), {}({}), or {}({})",
@@ -281,47 +967,23 @@ fn rename_files_confirm(
 
         match user_response {
             BatchConfirmResponse::Proceed => {} // fall through
-            BatchConfirmResponse::Skip => continue 'batch,
+            // skipping a batch would leave the run incomplete
+            BatchConfirmResponse::Skip if run.atomic => {
+                debug!("Cannot skip a batch in atomic mode. Halting to roll back.");
+                Err(RenameError::UserHalt)?
+            }
+            BatchConfirmResponse::Skip => {
+                batch.iter().for_each(|&idx| statuses[idx] = RenameStatus::Skipped);
+                continue 'batch;
+            }
             BatchConfirmResponse::Halt => Err(RenameError::UserHalt)?,
         }
 
         // rename batch
-        for (path, new_name) in batch {
-            let path = path.as_ref();
-            let new_name = new_name.as_ref();
-            'retry: loop {
-                let rename_res = do_rename(path, new_name, dry_run);
-                match (rename_res, err_mode) {
-                    (Ok(_), _) => {
-                        trace!("Rename from {path:?} to {new_name} successful.");
-                        success_count += 1;
-                        break 'retry;
-                    }
-                    (Err(err), ErrorHandlingMode::Ignore) => {
-                        debug!("Failed to rename {path:?} to {new_name}: {err}, ignoring.");
-                        break 'retry;
-                    }
-                    (Err(err), ErrorHandlingMode::Warn) => {
-                        debug!("Failed to rename {path:?} to {new_name}: {err}. Prompting.");
-                        println!(
-                            "Failed to rename {:?} to {}: {err}",
-                            format!("{path:?}").red(),
-                            new_name.red(),
-                        );
-                        let user_response = error_prompt("What to do with this file?", Some(OnErrorResponse::Skip))?;
-                        trace!("User selected \"{user_response}\"");
-
-                        match user_response {
-                            OnErrorResponse::Skip => break 'retry,
-                            OnErrorResponse::Retry => continue 'retry,
-                            OnErrorResponse::Halt => Err(RenameError::UserHalt)?,
-                        }
-                    }
-                    (Err(err), ErrorHandlingMode::Halt) => {
-                        debug!("Failed to rename {path:?} to {new_name}: {err}. Halting.");
-                        Err(err)?;
-                    }
-                }
+        for &idx in batch {
+            let new_name = pairs_list[idx].1.as_ref();
+            if run.rename_with_retry(idx, new_name, &mut statuses[idx])? {
+                success_count += 1;
             }
         }
     }
@@ -330,36 +992,181 @@ fn rename_files_confirm(
     Ok(success_count)
 }
 
-/// Perform rename on a single file.
-fn do_rename(path: &Path, new_name: &str, dry_run: bool) -> io::Result<()> {
-    trace!("Renaming {path:?} to {new_name}. Dry run: {dry_run}.");
-
-    let new_abs_path = {
-        let mut new_path = path
-            .parent()
-            .expect("paths should point to files at this point")
-            .to_owned();
-        new_path.push(new_name);
-        new_path
-    };
+/// Get the absolute path a file would have after being renamed to `new_name`.
+fn new_path_of(path: &Path, new_name: &OsStr) -> PathBuf {
+    let mut new_path = path
+        .parent()
+        .expect("paths should point to files at this point")
+        .to_owned();
+    new_path.push(new_name);
+    new_path
+}
+
+/// Check that every new name fits within the maximum name length of the filesystem it is on.
+///
+/// All offending names are reported at once, so that nothing is renamed
+/// if any name would be rejected. If `portable` is set, names must also fit on other platforms.
+pub fn check_name_lengths<P, S>(pairs: &[(P, S)], portable: Option<Portability>) -> Result<(), NameFinaliseError>
+where
+    P: AsRef<Path>,
+    S: AsRef<OsStr>,
+{
+    let mut limits = HashMap::new();
+    let mut too_long = vec![];
+    for (path, new_name) in pairs.iter() {
+        let (path, new_name) = (path.as_ref(), new_name.as_ref());
+        let dir = path.parent().expect("paths should point to files at this point");
+        let max = *limits.entry(dir).or_insert_with(|| {
+            let max = name_max(dir).unwrap_or_else(|err| {
+                debug!("Cannot query the maximum name length of {dir:?}: {err}. Skipping the check.");
+                None
+            });
+            trace!("Maximum name length of {dir:?} is {max:?}.");
+            max
+        });
+        let len = name_len(new_name);
+        if let Some(max) = max.filter(|&max| len > max) {
+            too_long.push((path.to_owned(), new_name.to_owned(), len, max));
+        } else if portable.is_some() && new_name.len() > PORTABLE_NAME_MAX {
+            // portable names are measured in bytes, which is never less than the native length
+            too_long.push((path.to_owned(), new_name.to_owned(), new_name.len(), PORTABLE_NAME_MAX));
+        }
+    }
+
+    if !too_long.is_empty() {
+        Err(NameFinaliseError::TooLong(too_long))?;
+    }
+    Ok(())
+}
+
+/// Get the length of `name` in the units its filesystem limits, i.e. bytes.
+#[cfg(not(windows))]
+fn name_len(name: &OsStr) -> usize {
+    name.len()
+}
+
+/// Get the length of `name` in the units its filesystem limits, i.e. UTF-16 units.
+#[cfg(windows)]
+fn name_len(name: &OsStr) -> usize {
+    use std::os::windows::ffi::OsStrExt;
+    name.encode_wide().count()
+}
 
-    if new_abs_path.try_exists()? {
-        Err(io::Error::new(
-            io::ErrorKind::AlreadyExists,
-            format!("renaming {path:?} to {new_abs_path:?} will overwrite an existing file"),
-        ))?;
+/// Get the maximum length in bytes of a name in `dir`, or `None` if it is unknown.
+#[cfg(unix)]
+fn name_max(dir: &Path) -> io::Result<Option<usize>> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let dir_c = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid NUL-terminated string and `stat` is a valid buffer,
+    // both of which outlive the call
+    let ret = unsafe { libc::statvfs(dir_c.as_ptr(), stat.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
     }
+    // SAFETY: `statvfs` succeeded, so `stat` is initialised
+    let max = unsafe { stat.assume_init() }.f_namemax;
+    Ok((max > 0).then_some(max as usize))
+}
+
+/// Get the maximum length of a name in `dir`, as measured by `name_len`, or `None` if it is unknown.
+///
+/// Most filesystems on other platforms (e.g. NTFS) limit names to 255 UTF-16 units.
+#[cfg(not(unix))]
+fn name_max(_dir: &Path) -> io::Result<Option<usize>> {
+    Ok(Some(255))
+}
+
+/// Perform rename on a single file.
+///
+/// Unless `allow_existing` is set, refuses to overwrite an existing file.
+/// Outside of dry run mode, this is enforced atomically by [`rename_no_replace`].
+fn do_rename(path: &Path, new_abs_path: &Path, dry_run: bool, allow_existing: bool) -> Result<(), RenameError> {
+    trace!("Renaming {path:?} to {new_abs_path:?}. Dry run: {dry_run}.");
+    let already_exists = || RenameError::AlreadyExists {
+        path: path.to_owned(),
+        new_abs_path: new_abs_path.to_owned(),
+    };
 
     if dry_run {
-        println!(
+        if !allow_existing && new_abs_path.try_exists()? {
+            Err(already_exists())?;
+        }
+        message!(
             "\tRename preview: {} -> {}",
             format!("{path:?}").yellow(),
             format!("{new_abs_path:?}").green(),
         );
-    } else {
-        trace!("New full path is {new_abs_path:?}");
+    } else if allow_existing {
         fs::rename(path, new_abs_path)?;
+    } else {
+        match rename_no_replace(path, new_abs_path) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Err(already_exists())?,
+            res => res?,
+        }
     }
 
     Ok(())
 }
+
+/// Rename `from` to `to`, failing with [`io::ErrorKind::AlreadyExists`] if `to` exists,
+/// without the window between the check and the rename.
+#[cfg(target_os = "linux")]
+fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let from_c = CString::new(from.as_os_str().as_bytes())?;
+    let to_c = CString::new(to.as_os_str().as_bytes())?;
+    // SAFETY: both pointers are valid NUL-terminated strings that outlive the call
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from_c.as_ptr(),
+            libc::AT_FDCWD,
+            to_c.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // the kernel or the filesystem does not support `RENAME_NOREPLACE`
+        Some(libc::EINVAL | libc::ENOSYS) => {
+            debug!("renameat2 is unsupported for {from:?}: {err}. Falling back to link and unlink.");
+            rename_via_link(from, to)
+        }
+        _ => Err(err),
+    }
+}
+
+/// Rename `from` to `to`, failing with [`io::ErrorKind::AlreadyExists`] if `to` exists,
+/// without the window between the check and the rename.
+#[cfg(not(target_os = "linux"))]
+fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    rename_via_link(from, to)
+}
+
+/// Rename `from` to `to` by hard linking and then unlinking the original.
+/// Creating a link never overwrites an existing file.
+///
+/// Anything other than a regular file (e.g. a directory or a symlink), or a file on a filesystem
+/// without hard link support, falls back to a non-atomic existence check followed by a regular rename.
+fn rename_via_link(from: &Path, to: &Path) -> io::Result<()> {
+    if from.symlink_metadata()?.is_file() {
+        match fs::hard_link(from, to) {
+            Ok(()) => return fs::remove_file(from),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return Err(err),
+            Err(err) => debug!("Cannot hard link {from:?} to {to:?}: {err}."),
+        }
+    }
+
+    debug!("Falling back to a non-atomic rename of {from:?} to {to:?}.");
+    if to.try_exists()? {
+        Err(io::ErrorKind::AlreadyExists)?;
+    }
+    fs::rename(from, to)
+}
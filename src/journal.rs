@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::crate_name;
+use itertools::Itertools;
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::io_helper::RenameStatus;
+
+#[derive(Debug)]
+pub enum JournalError {
+    IOError(io::Error),
+    JsonError(serde_json::Error),
+    NoStateDir,
+    NoJournal { dir: PathBuf },
+}
+impl From<io::Error> for JournalError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+impl From<serde_json::Error> for JournalError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::JsonError(err)
+    }
+}
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Self::IOError(err) => err.to_string(),
+            Self::JsonError(err) => err.to_string(),
+            Self::NoStateDir => "cannot determine the state directory of the current user".into(),
+            Self::NoJournal { dir } => format!("there are no journals in {dir:?}"),
+        };
+        write!(f, "Journal error: {repr}")
+    }
+}
+impl From<JournalError> for String {
+    fn from(err: JournalError) -> Self {
+        err.to_string()
+    }
+}
+
+/// A single rename action that was performed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// The original absolute path.
+    #[serde(with = "os_path")]
+    pub from: PathBuf,
+    /// The new absolute path.
+    #[serde(with = "os_path")]
+    pub to: PathBuf,
+}
+
+/// (De)serialise paths and names that may not be valid UTF-8.
+///
+/// Valid values are stored as plain strings, so that journals and mappings stay readable.
+/// Other values are stored byte-exact as `{"bytes": [...]}`.
+pub mod os_path {
+    use std::ffi::{OsStr, OsString};
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Str(String),
+        Bytes { bytes: Vec<u8> },
+    }
+
+    pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: AsRef<OsStr>,
+        S: Serializer,
+    {
+        let value = value.as_ref();
+        let repr = match value.to_str() {
+            Some(s) => Repr::Str(s.to_owned()),
+            None => Repr::Bytes {
+                bytes: to_bytes(value).ok_or_else(|| serde::ser::Error::custom(format!("{value:?} is not UTF-8")))?,
+            },
+        };
+        repr.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: From<OsString>,
+        D: Deserializer<'de>,
+    {
+        match Repr::deserialize(deserializer)? {
+            Repr::Str(s) => Ok(OsString::from(s).into()),
+            Repr::Bytes { bytes } => from_bytes(bytes)
+                .map(Into::into)
+                .ok_or_else(|| serde::de::Error::custom("byte paths are not supported on this platform")),
+        }
+    }
+
+    #[cfg(unix)]
+    fn to_bytes(value: &OsStr) -> Option<Vec<u8>> {
+        use std::os::unix::ffi::OsStrExt;
+        Some(value.as_bytes().to_vec())
+    }
+    #[cfg(not(unix))]
+    fn to_bytes(_value: &OsStr) -> Option<Vec<u8>> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+        use std::os::unix::ffi::OsStringExt;
+        Some(OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    fn from_bytes(_bytes: Vec<u8>) -> Option<OsString> {
+        None
+    }
+}
+
+/// The first line of a journal file, followed by one `JournalEntry` per line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct JournalHeader {
+    timestamp: u64,
+    options: Vec<String>,
+}
+
+/// A record of all renames performed in a single run, which can be used to undo them.
+#[derive(Debug)]
+pub struct Journal {
+    /// Seconds since the UNIX epoch at the start of the run.
+    pub timestamp: u64,
+    /// The command line arguments of the run.
+    pub options: Vec<String>,
+    pub entries: Vec<JournalEntry>,
+    /// The file every entry is appended to as soon as it is recorded,
+    /// so that a crash or interrupt does not lose the renames already performed.
+    file: Option<(PathBuf, File)>,
+}
+impl Journal {
+    /// Start a new empty journal for the current run, and create its file in the state directory.
+    pub fn create() -> Result<Self, JournalError> {
+        let mut journal = Self::new();
+        let dir = journal_dir()?;
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}-{}.jsonl", journal.timestamp, process::id()));
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        writeln!(file, "{}", serde_json::to_string(&journal.header())?)?;
+        debug!("Created journal {path:?}.");
+        journal.file = Some((path, file));
+        Ok(journal)
+    }
+
+    fn new() -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let options = env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        Self {
+            timestamp,
+            options,
+            entries: vec![],
+            file: None,
+        }
+    }
+
+    fn header(&self) -> JournalHeader {
+        JournalHeader {
+            timestamp: self.timestamp,
+            options: self.options.clone(),
+        }
+    }
+
+    /// Record a rename action that was successfully performed.
+    pub fn record(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) {
+        let entry = JournalEntry {
+            from: from.as_ref().to_owned(),
+            to: to.as_ref().to_owned(),
+        };
+        trace!("Recording {entry:?} in journal.");
+        if let Some((path, file)) = self.file.as_mut() {
+            // a failed append is caught up on by `finish`
+            let append_res = serde_json::to_string(&entry)
+                .map_err(io::Error::from)
+                .and_then(|line| writeln!(file, "{line}"));
+            if let Err(err) = append_res {
+                warn!("Failed to append to journal {path:?}: {err}");
+            }
+        }
+        self.entries.push(entry);
+    }
+
+    /// Finish writing the journal file, or remove it if every recorded rename has been reverted.
+    ///
+    /// Returns the path of the journal, if it is kept.
+    pub fn finish(self) -> Result<Option<PathBuf>, JournalError> {
+        let Some((path, file)) = &self.file else {
+            return Ok(None);
+        };
+        if self.net_entries().is_empty() {
+            trace!("Journal has no net renames; removing {path:?}.");
+            fs::remove_file(path)?;
+            return Ok(None);
+        }
+
+        // rewrite it in full, in case any append failed
+        file.sync_all()?;
+        self.write_to(path)?;
+        info!("Saved journal of {} renames to {path:?}.", self.entries.len());
+        Ok(Some(path.clone()))
+    }
+
+    /// Load a journal from a file.
+    ///
+    /// The last line may be incomplete if the run was cut short while writing it, in which case it is ignored.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, JournalError> {
+        let path = path.as_ref();
+        debug!("Loading journal from {path:?}.");
+        let content = fs::read_to_string(path)?;
+        let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+        let header: JournalHeader = serde_json::from_str(lines.next().unwrap_or_default())?;
+        let mut entries = vec![];
+        for (line_idx, line) in lines.with_position() {
+            match (serde_json::from_str(line), line_idx) {
+                (Ok(entry), _) => entries.push(entry),
+                (Err(err), itertools::Position::Last | itertools::Position::Only) if err.is_eof() => {
+                    warn!("Ignoring the incomplete last line of journal {path:?}.");
+                }
+                (Err(err), _) => Err(err)?,
+            }
+        }
+        Ok(Self {
+            timestamp: header.timestamp,
+            options: header.options,
+            entries,
+            file: None,
+        })
+    }
+
+    /// Collapse the recorded renames into a single rename per file,
+    /// skipping any intermediate temporary names.
+    pub fn net_entries(&self) -> Vec<JournalEntry> {
+        let mut net: Vec<JournalEntry> = vec![];
+        let mut by_current_path: HashMap<&Path, usize> = HashMap::new();
+        for JournalEntry { from, to } in self.entries.iter() {
+            match by_current_path.remove(from.as_path()) {
+                Some(idx) => {
+                    net[idx].to = to.clone();
+                    by_current_path.insert(to, idx);
+                }
+                None => {
+                    by_current_path.insert(to, net.len());
+                    net.push(JournalEntry {
+                        from: from.clone(),
+                        to: to.clone(),
+                    });
+                }
+            }
+        }
+        net.retain(|JournalEntry { from, to }| from != to);
+        net
+    }
+
+    /// Get the `path`-`new name` pairs that revert every recorded rename,
+    /// in the reverse order of the original run.
+    pub fn reverse_pairs(&self) -> Vec<(PathBuf, OsString)> {
+        self.net_entries()
+            .into_iter()
+            .rev()
+            .map(|JournalEntry { from, to }| {
+                let original_name = from
+                    .file_name()
+                    .expect("journalled paths should point to files")
+                    .to_owned();
+                (to, original_name)
+            })
+            .collect()
+    }
+
+    /// Remove all entries that have been reverted from the journal at `path`.
+    /// `statuses` are the outcomes of the renames returned by `reverse_pairs`.
+    ///
+    /// The journal file is deleted if no entries remain.
+    pub fn prune_reverted(mut self, path: impl AsRef<Path>, statuses: &[RenameStatus]) -> Result<(), JournalError> {
+        let path = path.as_ref();
+        let mut net = self.net_entries();
+        // `reverse_pairs` is in reverse order
+        net.reverse();
+        self.entries = net
+            .into_iter()
+            .zip(statuses)
+            .filter_map(|(entry, status)| (status != &RenameStatus::Renamed).then_some(entry))
+            .rev()
+            .collect();
+
+        if self.entries.is_empty() {
+            debug!("All entries reverted; removing journal {path:?}.");
+            fs::remove_file(path)?;
+        } else {
+            debug!(
+                "{} entries not reverted; updating journal {path:?}.",
+                self.entries.len()
+            );
+            self.write_to(path)?;
+        }
+        Ok(())
+    }
+
+    fn write_to(&self, path: &Path) -> Result<(), JournalError> {
+        let mut lines = vec![serde_json::to_string(&self.header())?];
+        for entry in self.entries.iter() {
+            lines.push(serde_json::to_string(entry)?);
+        }
+        fs::write(path, lines.iter().map(|line| format!("{line}\n")).join(""))?;
+        Ok(())
+    }
+}
+
+/// The directory in which journals are stored.
+pub fn journal_dir() -> Result<PathBuf, JournalError> {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|dir| dir.join(crate_name!()).join("journal"))
+        .ok_or(JournalError::NoStateDir)
+}
+
+/// Find the most recently written journal.
+pub fn latest_journal() -> Result<PathBuf, JournalError> {
+    let dir = journal_dir()?;
+    let no_journal = || JournalError::NoJournal { dir: dir.clone() };
+
+    if !dir.try_exists()? {
+        Err(no_journal())?;
+    }
+    let entries: Vec<_> = fs::read_dir(&dir)?.try_collect()?;
+    entries
+        .into_iter()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "jsonl"))
+        .map(|entry| Ok::<_, io::Error>((entry.metadata()?.modified()?, entry.path())))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .max()
+        .map(|(_, path)| path)
+        .ok_or_else(no_journal)
+}
@@ -5,17 +5,38 @@ mod char_set;
 mod cli;
 mod compute;
 mod io_helper;
+mod journal;
+mod mapping;
+mod portable;
+mod template;
 mod util;
+mod word_list;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 use clap::{crate_name, CommandFactory, Parser};
-use compute::generate_random_names;
-use log::debug;
+use itertools::Itertools;
+use log::{debug, info, warn};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use yansi::Paint;
 
 use crate::{
-    cli::{CliArgs, SubCmd},
-    compute::finalise_names,
-    io_helper::{dedup_paths, rename_files},
+    char_set::CharSet,
+    cli::{CaseInsensitivity, CliArgs, ConfirmMode, ErrorHandlingMode, GeneratorSelection, MappingFormat, SubCmd},
+    compute::{generate_random_names, prepare_names, NameFinaliser, NameGenerator, DEFAULT_NAME_LENGTH},
+    io_helper::{
+        case_insensitive_dirs, check_name_lengths, dedup_paths, expand_paths, list_dir_names, read_file_list,
+        rename_files, CaseInsensitiveDirs, RenameOptions, RenameStatus,
+    },
+    journal::{latest_journal, Journal},
+    mapping::{check_duplicates, check_mapping_records, read_mapping, write_mapping, MappingRecord},
+    template::Placeholder,
+    util::{message, redirect_messages_to_stderr, ExtensionMode},
+    word_list::WordList,
 };
 
 fn main() -> Result<(), String> {
@@ -33,20 +54,54 @@ fn main() -> Result<(), String> {
         confirm_mode,
         confirm_batch_size,
         dry_run,
+        mapping_file,
+        output_format,
         extension_mode_selection,
         static_ext,
+        group_sidecars,
         error_handling_mode,
+        atomic,
+        case_insensitivity,
+        portable,
         force_generation_strategy,
+        seed,
+        generator_selection,
+        word_list,
+        word_count,
+        word_separator,
+        hmac_key,
+        hmac_input,
         name_length,
         name_prefix,
         name_suffix,
-        char_set_selection,
+        template,
+        char_set_expr,
         custom_chars,
         case,
+        no_ambiguous,
+        files_from,
+        null_separated,
+        recursive,
+        include,
+        exclude,
+        max_depth,
         verbosity: _,
-        files,
+        mut files,
     } = args;
 
+    let rename_options = RenameOptions {
+        dry_run,
+        confirm_mode,
+        confirm_batch_size,
+        err_mode: error_handling_mode,
+        atomic,
+    };
+
+    // keep the mapping file on stdout machine-readable
+    if mapping_file.as_deref() == Some(Path::new("-")) {
+        redirect_messages_to_stderr();
+    }
+
     if let Some(sub_cmd) = sub_cmd {
         match sub_cmd {
             SubCmd::Complete { shell_type } => {
@@ -58,39 +113,288 @@ fn main() -> Result<(), String> {
                 );
                 return Ok(());
             }
+            SubCmd::Apply { mapping, format } => {
+                let format = format.unwrap_or_else(|| MappingFormat::from_path(&mapping));
+                let pairs = read_mapping(&mapping, format)?
+                    .into_iter()
+                    .map(|MappingRecord { path, new_name, .. }| (path, new_name))
+                    .collect_vec();
+                check_name_lengths(&pairs, portable)?;
+                if dry_run {
+                    message!("You are in {}. Your files will not be touched.", "DRY RUN MODE".red());
+                }
+
+                let ci_dirs = detect_case_insensitive_dirs(&pairs, case_insensitivity);
+                check_duplicates(&pairs, &ci_dirs)?;
+                let mut journal = (!dry_run).then(Journal::create).transpose()?;
+                let mut statuses = vec![RenameStatus::Pending; pairs.len()];
+                let rename_res = rename_files(&pairs, &ci_dirs, rename_options, journal.as_mut(), &mut statuses);
+                save_journal(journal)?;
+                let success_count = rename_res?;
+
+                print_summary("Renamed", success_count, dry_run);
+                return Ok(());
+            }
+            SubCmd::Undo { journal: journal_path } => {
+                let journal_path = match journal_path {
+                    Some(path) => path,
+                    None => latest_journal()?,
+                };
+                let journal = Journal::load(&journal_path)?;
+                message!("Undoing journal {}.", format!("{journal_path:?}").yellow());
+                if dry_run {
+                    message!("You are in {}. Your files will not be touched.", "DRY RUN MODE".red());
+                }
+
+                let reverse_pairs = journal.reverse_pairs();
+                let ci_dirs = detect_case_insensitive_dirs(&reverse_pairs, case_insensitivity);
+                let mut statuses = vec![RenameStatus::Pending; reverse_pairs.len()];
+                let rename_res = rename_files(&reverse_pairs, &ci_dirs, rename_options, None, &mut statuses);
+                if !dry_run {
+                    journal.prune_reverted(&journal_path, &statuses)?;
+                }
+                let success_count = rename_res?;
+
+                print_summary("Reverted", success_count, dry_run);
+                return Ok(());
+            }
         }
     }
 
     if dry_run {
-        println!("You are in {}. Your files will not be touched.", "DRY RUN MODE".red());
+        message!("You are in {}. Your files will not be touched.", "DRY RUN MODE".red());
     }
 
+    if let Some(source) = files_from {
+        // prompts read from stdin too, which would already be exhausted
+        if source == Path::new("-")
+            && (confirm_mode != ConfirmMode::None || error_handling_mode == ErrorHandlingMode::Warn)
+        {
+            Err("`--files-from -` reads stdin, so it cannot be used with prompts. \
+                Set `--confirm none` and `--error-handling-mode` to `ignore` or `halt`.")?;
+        }
+        let mut listed_files = read_file_list(&source, null_separated)
+            .map_err(|err| format!("Failed to read file list from {source:?}: {err}"))?;
+        files.append(&mut listed_files);
+    }
+    let files = if recursive {
+        expand_paths(&files, &include, &exclude, max_depth, error_handling_mode)?
+    } else {
+        files
+    };
     let files_unique = dedup_paths(&files, error_handling_mode)?;
 
-    let char_set = (char_set_selection, custom_chars, case).try_into()?;
+    let mut char_set: CharSet = (char_set_expr, custom_chars, case).try_into()?;
+    if no_ambiguous {
+        char_set = char_set.without_ambiguous()?;
+    }
     debug!("Character set is {char_set}");
-    let random_name_pairs = generate_random_names(&files_unique, char_set, name_length, force_generation_strategy)?;
+    debug!("Name template is \"{template}\"");
+    let hmac_key = match hmac_key {
+        Some(path) if generator_selection == GeneratorSelection::Hmac => {
+            Some(fs::read(&path).map_err(|err| format!("Failed to read HMAC key from {path:?}: {err}"))?)
+        }
+        Some(_) => {
+            warn!("`--hmac-key` is specified but the generator is not `hmac`");
+            None
+        }
+        None => None,
+    };
+    let is_words = generator_selection == GeneratorSelection::Words;
+    let word_list = match word_list {
+        Some(path) if is_words => {
+            let list =
+                fs::read_to_string(&path).map_err(|err| format!("Failed to read word list from {path:?}: {err}"))?;
+            Some(
+                list.parse::<WordList>()
+                    .map_err(|err| format!("Invalid word list {path:?}: {err}"))?,
+            )
+        }
+        Some(_) => {
+            warn!("`--wordlist` is specified but the generator is not `words`");
+            None
+        }
+        None => None,
+    };
+    if let Some(portability) = portable {
+        debug!("Checking that every part of the names is portable to {portability:?}");
+        portability.check_part("character set", &char_set.get_char_set().concat(), false)?;
+        for word in word_list.iter().flat_map(WordList::words) {
+            portability.check_part("word", word, false)?;
+        }
+        for (what, value, at_end) in [
+            ("prefix", &name_prefix, false),
+            ("suffix", &name_suffix, false),
+            ("static extension", &static_ext, true),
+        ] {
+            if let Some(value) = value {
+                portability.check_part(what, value, at_end)?;
+            }
+        }
+    }
+    // for word lists, the length of each `{rand}` is measured in words
+    let name_length = match (generator_selection, name_length) {
+        (GeneratorSelection::Words, _) => word_count,
+        (_, Some(len)) => len,
+        // just wide enough for the largest index
+        (GeneratorSelection::Shuffle, None) => files_unique.len().saturating_sub(1).to_string().len(),
+        (_, None) => DEFAULT_NAME_LENGTH,
+    };
+    let generator = NameGenerator::new(
+        generator_selection,
+        char_set,
+        word_list,
+        &template,
+        name_length,
+        hmac_key,
+        hmac_input,
+    )?;
+    debug!("Generating {generator} per name");
+    let placeholders = template.placeholders().collect_vec();
+    if name_prefix.is_some() && !placeholders.contains(&&Placeholder::Prefix) {
+        warn!("`--prefix` is specified but not used by the template");
+    }
+    if name_suffix.is_some() && !placeholders.contains(&&Placeholder::Suffix) {
+        warn!("`--suffix` is specified but not used by the template");
+    }
 
     let extension_mode = (extension_mode_selection, static_ext).try_into()?;
     debug!("Extension mode is {extension_mode}");
-    let finalised_name_pairs = finalise_names(
-        random_name_pairs,
-        name_prefix,
-        name_suffix,
-        extension_mode,
+    if group_sidecars {
+        // otherwise, every file in a group would receive exactly the same name
+        if !matches!(
+            extension_mode,
+            ExtensionMode::KeepAll | ExtensionMode::KeepLast | ExtensionMode::KnownCompound(_)
+        ) {
+            Err("`--group-sidecars` requires `--ext-mode` to be `keep-all`, `keep-last` or `known-compound`")?;
+        }
+        if !placeholders.contains(&&Placeholder::Ext) {
+            Err("`--group-sidecars` requires the template to contain `{ext}`")?;
+        }
+    }
+    let prepared_names = prepare_names(
+        &files_unique,
+        &template,
+        &generator,
+        &extension_mode,
+        group_sidecars,
         error_handling_mode,
     )?;
 
-    let success_count = rename_files(
-        &finalised_name_pairs,
-        dry_run,
-        confirm_mode,
-        confirm_batch_size,
-        error_handling_mode,
+    // names must not collide when copied to a case-insensitive platform later
+    let case_insensitivity = match (case_insensitivity, portable) {
+        (CaseInsensitivity::Auto, Some(portability)) if portability.is_case_insensitive() => CaseInsensitivity::Always,
+        (case_insensitivity, _) => case_insensitivity,
+    };
+    let target_dirs = prepared_names.iter().filter_map(|(path, _)| path.parent());
+    let mut taken_names = list_dir_names(target_dirs, case_insensitivity)
+        .map_err(|err| format!("Failed to list existing file names: {err}"))?;
+    // the current names of the files being renamed will be vacated, so they can be reused
+    for (path, _) in prepared_names.iter() {
+        if let (Some(dir), Some(name)) = (path.parent(), path.file_name()) {
+            if let Some(dir_names) = taken_names.get_mut(dir) {
+                dir_names.remove(name);
+            }
+        }
+    }
+
+    let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+    info!("Using seed {seed}");
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let finaliser = NameFinaliser::new(
+        &template,
+        generator.placeholder_length(name_length),
+        is_words.then_some(word_separator.as_str()),
+        name_prefix.as_deref(),
+        name_suffix.as_deref(),
+        portable,
+    );
+    let finalised_name_pairs = generate_random_names(
+        &prepared_names,
+        &generator,
+        force_generation_strategy,
+        &mut rng,
+        &finaliser,
+        &mut taken_names,
     )?;
+    check_name_lengths(&finalised_name_pairs, portable)?;
+    // fail before renaming anything, rather than losing the mapping afterwards
+    let mapping_dest = mapping_file.map(|dest| {
+        let format = output_format.unwrap_or_else(|| MappingFormat::from_path(&dest));
+        (dest, format)
+    });
+    if let Some((_, format)) = mapping_dest {
+        let records = finalised_name_pairs
+            .iter()
+            .map(|(path, new_name)| MappingRecord::new(path, new_name, &RenameStatus::Pending))
+            .collect_vec();
+        check_mapping_records(format, &records)?;
+    }
+
+    let mut journal = (!dry_run).then(Journal::create).transpose()?;
+    let mut statuses = vec![RenameStatus::Pending; finalised_name_pairs.len()];
+    let rename_res = rename_files(
+        &finalised_name_pairs,
+        &case_insensitive_dirs(&taken_names),
+        rename_options,
+        journal.as_mut(),
+        &mut statuses,
+    );
+    // save the journal even if the run is cut short, so that completed renames can be undone
+    save_journal(journal)?;
+    if let Some((dest, format)) = mapping_dest {
+        let records = finalised_name_pairs
+            .iter()
+            .zip(&statuses)
+            .map(|((path, new_name), status)| MappingRecord::new(path, new_name, status))
+            .collect_vec();
+        write_mapping(&dest, format, &records)?;
+    }
+    let success_count = rename_res?;
+
+    print_summary("Renamed", success_count, dry_run);
+
+    Ok(())
+}
+
+/// Find which directories containing the files in `pairs` are case-insensitive.
+///
+/// Directories that cannot be listed are assumed to be case-sensitive; renaming within them will fail anyway.
+fn detect_case_insensitive_dirs<S>(
+    pairs: &[(PathBuf, S)],
+    case_insensitivity: CaseInsensitivity,
+) -> CaseInsensitiveDirs {
+    pairs
+        .iter()
+        .filter_map(|(path, _)| path.parent())
+        .unique()
+        .filter_map(|dir| match list_dir_names([dir], case_insensitivity) {
+            Ok(taken) => Some(taken),
+            Err(err) => {
+                debug!("Failed to list existing file names in {dir:?}: {err}");
+                None
+            }
+        })
+        .flat_map(|taken| case_insensitive_dirs(&taken))
+        .collect()
+}
+
+/// Save the journal of a run, if any, and tell the user how to undo it.
+fn save_journal(journal: Option<Journal>) -> Result<(), String> {
+    if let Some(journal_path) = journal.map(Journal::finish).transpose()?.flatten() {
+        message!(
+            "Saved journal to {}. Run `{} undo` to revert.",
+            format!("{journal_path:?}").yellow(),
+            crate_name!()
+        );
+    }
+    Ok(())
+}
 
-    println!(
-        "Renamed {} files{}. Done.",
+/// Print how many files were processed.
+fn print_summary(verb: &str, success_count: usize, dry_run: bool) {
+    message!(
+        "{verb} {} files{}. Done.",
         success_count.green(),
         if dry_run {
             format!(" ({})", "DRY RUN".red())
@@ -98,6 +402,4 @@ fn main() -> Result<(), String> {
             "".into()
         }
     );
-
-    Ok(())
 }
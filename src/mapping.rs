@@ -0,0 +1,290 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fmt, fs, io,
+    path::{self, Path, PathBuf},
+};
+
+use itertools::Itertools;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::MappingFormat,
+    io_helper::{path_key, CaseInsensitiveDirs, RenameStatus},
+    journal::os_path,
+};
+
+#[derive(Debug)]
+pub enum MappingError {
+    IOError(io::Error),
+    JsonError(serde_json::Error),
+    CsvError(csv::Error),
+    InvalidName {
+        path: PathBuf,
+        new_name: OsString,
+    },
+    NotUtf8 {
+        path: PathBuf,
+    },
+    /// Paths listed more than once, and new paths given to more than one file.
+    Duplicates {
+        paths: Vec<PathBuf>,
+        new_paths: Vec<PathBuf>,
+    },
+}
+impl From<io::Error> for MappingError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+impl From<serde_json::Error> for MappingError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::JsonError(err)
+    }
+}
+impl From<csv::Error> for MappingError {
+    fn from(err: csv::Error) -> Self {
+        Self::CsvError(err)
+    }
+}
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Self::IOError(err) => err.to_string(),
+            Self::JsonError(err) => err.to_string(),
+            Self::CsvError(err) => err.to_string(),
+            Self::InvalidName { path, new_name } => {
+                format!("{new_name:?} (for {path:?}) is not a valid file name")
+            }
+            Self::NotUtf8 { path } => format!(
+                "the path or new name of {path:?} is not valid UTF-8, so it cannot be written as CSV or TSV; \
+                use JSON instead"
+            ),
+            Self::Duplicates { paths, new_paths } => {
+                let paths = paths.iter().map(|path| format!("\t{path:?} is listed more than once"));
+                let new_paths = new_paths
+                    .iter()
+                    .map(|path| format!("\t{path:?} is the new path of more than one file"));
+                format!(
+                    "the mapping contains duplicates:\n{}",
+                    paths.chain(new_paths).join("\n")
+                )
+            }
+        };
+        write!(f, "Mapping file error: {repr}")
+    }
+}
+impl From<MappingError> for String {
+    fn from(err: MappingError) -> Self {
+        err.to_string()
+    }
+}
+
+/// A single row of a mapping file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappingRecord {
+    /// The original absolute path.
+    #[serde(with = "os_path")]
+    pub path: PathBuf,
+    /// The new file name.
+    #[serde(with = "os_path")]
+    pub new_name: OsString,
+    /// The outcome of the rename.
+    #[serde(default)]
+    pub status: Option<String>,
+    /// The error encountered during the rename, if any.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+impl MappingRecord {
+    pub fn new(path: impl AsRef<Path>, new_name: impl AsRef<OsStr>, status: &RenameStatus) -> Self {
+        let error = match status {
+            RenameStatus::Failed(err) => Some(err.clone()),
+            _ => None,
+        };
+        Self {
+            path: path.as_ref().to_owned(),
+            new_name: new_name.as_ref().to_owned(),
+            status: Some(status.to_string()),
+            error,
+        }
+    }
+}
+
+/// A mapping row as stored in CSV and TSV files, which can only hold UTF-8.
+///
+/// Unlike JSON, CSV fields are untyped, so the byte representation of `os_path` is not available.
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvRecord {
+    path: String,
+    new_name: String,
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+impl TryFrom<&MappingRecord> for CsvRecord {
+    type Error = MappingError;
+
+    fn try_from(record: &MappingRecord) -> Result<Self, Self::Error> {
+        let (Some(path), Some(new_name)) = (record.path.to_str(), record.new_name.to_str()) else {
+            return Err(MappingError::NotUtf8 {
+                path: record.path.clone(),
+            });
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            new_name: new_name.to_owned(),
+            status: record.status.clone(),
+            error: record.error.clone(),
+        })
+    }
+}
+impl From<CsvRecord> for MappingRecord {
+    fn from(record: CsvRecord) -> Self {
+        Self {
+            path: record.path.into(),
+            new_name: record.new_name.into(),
+            status: record.status,
+            error: record.error,
+        }
+    }
+}
+
+/// Check that every record can be written in `format` without loss.
+pub fn check_mapping_records(format: MappingFormat, records: &[MappingRecord]) -> Result<(), MappingError> {
+    match format {
+        MappingFormat::Json => Ok(()),
+        MappingFormat::Csv | MappingFormat::Tsv => records.iter().try_for_each(|record| {
+            CsvRecord::try_from(record)?;
+            Ok(())
+        }),
+    }
+}
+
+impl MappingFormat {
+    /// Guess the format from the extension of `path`, defaulting to JSON.
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Self::Csv,
+            Some(ext) if ext.eq_ignore_ascii_case("tsv") => Self::Tsv,
+            _ => Self::Json,
+        }
+    }
+
+    fn csv_delimiter(&self) -> u8 {
+        match self {
+            Self::Tsv => b'\t',
+            _ => b',',
+        }
+    }
+}
+
+/// Check that no file is listed more than once, and that no two files would get the same new path.
+///
+/// All duplicates are reported at once, so that nothing is renamed if the mapping is inconsistent.
+/// Paths in `ci_dirs` are compared ignoring case.
+pub fn check_duplicates<S>(pairs: &[(PathBuf, S)], ci_dirs: &CaseInsensitiveDirs) -> Result<(), MappingError>
+where
+    S: AsRef<OsStr>,
+{
+    fn repeated(paths: impl Iterator<Item = PathBuf>, ci_dirs: &CaseInsensitiveDirs) -> Vec<PathBuf> {
+        paths
+            .into_group_map_by(|path| path_key(path, ci_dirs))
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .map(|group| group[0].clone())
+            .sorted()
+            .collect()
+    }
+
+    let paths = repeated(pairs.iter().map(|(path, _)| path.clone()), ci_dirs);
+    let new_paths = repeated(
+        pairs.iter().map(|(path, new_name)| path.with_file_name(new_name)),
+        ci_dirs,
+    );
+    if !paths.is_empty() || !new_paths.is_empty() {
+        Err(MappingError::Duplicates { paths, new_paths })?;
+    }
+    Ok(())
+}
+
+/// Write the mapping records to `dest`, or to stdout if `dest` is `-`.
+pub fn write_mapping(
+    dest: impl AsRef<Path>,
+    format: MappingFormat,
+    records: &[MappingRecord],
+) -> Result<(), MappingError> {
+    let dest = dest.as_ref();
+    debug!("Writing {} mapping records to {dest:?} as {format:?}.", records.len());
+
+    let mut buf = vec![];
+    match format {
+        MappingFormat::Json => {
+            serde_json::to_writer_pretty(&mut buf, records)?;
+            buf.push(b'\n');
+        }
+        MappingFormat::Csv | MappingFormat::Tsv => {
+            let mut writer = csv::WriterBuilder::new()
+                .delimiter(format.csv_delimiter())
+                .from_writer(&mut buf);
+            for record in records {
+                writer.serialize(CsvRecord::try_from(record)?)?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    if dest == Path::new("-") {
+        io::Write::write_all(&mut io::stdout().lock(), &buf)?;
+    } else {
+        fs::write(dest, buf)?;
+        info!("Wrote mapping to {dest:?}.");
+    }
+    Ok(())
+}
+
+/// Read mapping records from `src`, or from stdin if `src` is `-`.
+///
+/// Relative paths are resolved against the current directory. Every new name must be
+/// a plain file name; moving files to another directory is not supported.
+pub fn read_mapping(src: impl AsRef<Path>, format: MappingFormat) -> Result<Vec<MappingRecord>, MappingError> {
+    let src = src.as_ref();
+    debug!("Reading mapping from {src:?} as {format:?}.");
+
+    let buf = if src == Path::new("-") {
+        let mut buf = vec![];
+        io::Read::read_to_end(&mut io::stdin().lock(), &mut buf)?;
+        buf
+    } else {
+        fs::read(src)?
+    };
+
+    let records: Vec<MappingRecord> = match format {
+        MappingFormat::Json => serde_json::from_slice(&buf)?,
+        MappingFormat::Csv | MappingFormat::Tsv => csv::ReaderBuilder::new()
+            .delimiter(format.csv_delimiter())
+            .from_reader(buf.as_slice())
+            .deserialize::<CsvRecord>()
+            .map_ok(MappingRecord::from)
+            .collect::<Result<_, _>>()?,
+    };
+
+    let records = records
+        .into_iter()
+        .map(|mut record| {
+            let is_plain_name = Path::new(&record.new_name).file_name() == Some(record.new_name.as_os_str());
+            if !is_plain_name {
+                return Err(MappingError::InvalidName {
+                    path: record.path,
+                    new_name: record.new_name,
+                });
+            }
+            record.path = path::absolute(&record.path)?;
+            Ok(record)
+        })
+        .collect::<Result<Vec<_>, MappingError>>()?;
+
+    debug!("Read {} mapping records.", records.len());
+    Ok(records)
+}
@@ -0,0 +1,127 @@
+use std::{error::Error, fmt};
+
+use itertools::Itertools;
+
+use crate::cli::Portability;
+
+/// The maximum length in bytes of a portable name.
+///
+/// Every targeted filesystem allows at least 255 bytes (or 255 UTF-16 units, which is never less).
+pub const PORTABLE_NAME_MAX: usize = 255;
+
+/// Characters forbidden on Windows, in addition to control characters.
+static WINDOWS_FORBIDDEN: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Device names reserved on Windows, regardless of case and extension.
+static WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortabilityError {
+    ForbiddenChars {
+        what: String,
+        value: String,
+        chars: Vec<char>,
+    },
+    TrailingDotOrSpace {
+        what: String,
+        value: String,
+    },
+}
+impl Error for PortabilityError {}
+impl fmt::Display for PortabilityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Self::ForbiddenChars { what, value, chars } => {
+                let chars = chars.iter().map(|c| format!("\'{}\'", c.escape_debug())).join(", ");
+                format!("the {what} \"{value}\" contains characters that are not portable: {chars}")
+            }
+            Self::TrailingDotOrSpace { what, value } => {
+                format!("the {what} \"{value}\" ends with a dot or a space, which is not portable")
+            }
+        };
+        write!(f, "{repr}")
+    }
+}
+impl From<PortabilityError> for String {
+    fn from(err: PortabilityError) -> Self {
+        err.to_string()
+    }
+}
+
+impl Portability {
+    /// Whether names are restricted to the POSIX portable filename character set, i.e. `[A-Za-z0-9._-]`.
+    fn posix(self) -> bool {
+        matches!(self, Self::Posix | Self::All)
+    }
+    /// Whether the rules of Windows apply.
+    fn windows(self) -> bool {
+        matches!(self, Self::Windows | Self::Fat | Self::All)
+    }
+    /// Whether the additional rules of FAT apply.
+    fn fat(self) -> bool {
+        matches!(self, Self::Fat | Self::All)
+    }
+
+    /// Whether names differing only in case are the same file on the targeted platforms.
+    pub fn is_case_insensitive(self) -> bool {
+        self.windows()
+    }
+
+    fn is_forbidden(self, c: char) -> bool {
+        c == '/'
+            || c == '\0'
+            || (self.posix() && !(c.is_ascii_alphanumeric() || ['.', '_', '-'].contains(&c)))
+            || (self.windows() && (c.is_ascii_control() || WINDOWS_FORBIDDEN.contains(&c)))
+            || (self.fat() && c == '\x7f')
+    }
+
+    fn is_reserved(self, name: &str) -> bool {
+        let stem = name.split('.').next().unwrap_or_default().trim_end_matches(' ');
+        self.windows()
+            && WINDOWS_RESERVED
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    }
+
+    /// Check that `value`, which is part of a name, only contains portable characters.
+    ///
+    /// If `at_end` is set, `value` is also checked as the end of a name.
+    pub fn check_part(self, what: &str, value: &str, at_end: bool) -> Result<(), PortabilityError> {
+        let chars = value.chars().filter(|&c| self.is_forbidden(c)).unique().collect_vec();
+        if !chars.is_empty() {
+            Err(PortabilityError::ForbiddenChars {
+                what: what.into(),
+                value: value.into(),
+                chars,
+            })?;
+        }
+        if at_end && self.windows() && value.ends_with(['.', ' ']) {
+            Err(PortabilityError::TrailingDotOrSpace {
+                what: what.into(),
+                value: value.into(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Make a complete name portable.
+    ///
+    /// Forbidden characters are removed, and names that would otherwise be invalid
+    /// (e.g. reserved device names) are escaped with a leading underscore.
+    pub fn sanitise(self, name: &str) -> String {
+        let mut name = name.chars().filter(|&c| !self.is_forbidden(c)).collect::<String>();
+        if self.windows() {
+            name.truncate(name.trim_end_matches(['.', ' ']).len());
+        }
+        if (self.posix() && name.starts_with('-'))
+            || self.is_reserved(&name)
+            || matches!(name.as_str(), "" | "." | "..")
+        {
+            name.insert(0, '_');
+        }
+        name
+    }
+}
@@ -0,0 +1,210 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use chrono::format::{Item, StrftimeItems};
+use itertools::Itertools;
+
+/// The template used when `--template` is not specified.
+/// This is equivalent to the behaviour of `--prefix` and `--suffix` alone.
+pub const DEFAULT_TEMPLATE: &str = "{prefix}{rand}{suffix}.{ext}";
+/// The date format used by `{date}` when none is specified.
+pub const DEFAULT_DATE_FORMAT: &str = "%Y%m%d";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseNameTemplateError {
+    UnclosedPlaceholder,
+    UnmatchedBrace,
+    UnknownPlaceholder(String),
+    BadPlaceholderArg { name: String, arg: String },
+    NoRandom,
+}
+impl Error for ParseNameTemplateError {}
+impl fmt::Display for ParseNameTemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Self::UnclosedPlaceholder => "the template contains an unclosed `{`".into(),
+            Self::UnmatchedBrace => "the template contains an unmatched `}`; use `}}` for a literal `}`".into(),
+            Self::UnknownPlaceholder(name) => format!("the template contains an unknown placeholder `{{{name}}}`"),
+            Self::BadPlaceholderArg { name, arg } => {
+                format!("\"{arg}\" is not a valid argument for the placeholder `{{{name}}}`")
+            }
+            Self::NoRandom => "the template must contain at least one `{rand}` placeholder".into(),
+        };
+        write!(f, "{repr}")
+    }
+}
+
+/// A single placeholder in a name template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Placeholder {
+    /// A chunk of random characters, optionally with an explicit length.
+    Random(Option<usize>),
+    /// The original file name without its extension.
+    Stem,
+    /// The name of the parent directory.
+    Parent,
+    /// The position of the file in the input list, zero-padded to a width.
+    Index { width: usize },
+    /// The date of the run, in `strftime` format.
+    Date(String),
+    /// The new file extension.
+    Ext,
+    /// The value of `--prefix`.
+    Prefix,
+    /// The value of `--suffix`.
+    Suffix,
+}
+impl FromStr for Placeholder {
+    type Err = ParseNameTemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ParseNameTemplateError as E;
+
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        let bad_arg = |arg: &str| E::BadPlaceholderArg {
+            name: name.into(),
+            arg: arg.into(),
+        };
+
+        Ok(match (name, arg) {
+            ("rand", None) => Self::Random(None),
+            ("rand", Some(arg)) => match arg.parse() {
+                Ok(len) if len > 0 => Self::Random(Some(len)),
+                _ => Err(bad_arg(arg))?,
+            },
+            ("stem", None) => Self::Stem,
+            ("parent", None) => Self::Parent,
+            ("index", None) => Self::Index { width: 0 },
+            ("index", Some(arg)) => match arg.parse() {
+                Ok(width) => Self::Index { width },
+                Err(_) => Err(bad_arg(arg))?,
+            },
+            ("date", None) => Self::Date(DEFAULT_DATE_FORMAT.into()),
+            ("date", Some(arg)) => {
+                if StrftimeItems::new(arg).contains(&Item::Error) {
+                    Err(bad_arg(arg))?;
+                }
+                Self::Date(arg.into())
+            }
+            ("ext", None) => Self::Ext,
+            ("prefix", None) => Self::Prefix,
+            ("suffix", None) => Self::Suffix,
+            ("stem" | "parent" | "ext" | "prefix" | "suffix", Some(arg)) => Err(bad_arg(arg))?,
+            (other, _) => Err(E::UnknownPlaceholder(other.into()))?,
+        })
+    }
+}
+impl fmt::Display for Placeholder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Self::Random(None) => "rand".into(),
+            Self::Random(Some(len)) => format!("rand:{len}"),
+            Self::Stem => "stem".into(),
+            Self::Parent => "parent".into(),
+            Self::Index { width: 0 } => "index".into(),
+            Self::Index { width } => format!("index:{width}"),
+            Self::Date(format) => format!("date:{format}"),
+            Self::Ext => "ext".into(),
+            Self::Prefix => "prefix".into(),
+            Self::Suffix => "suffix".into(),
+        };
+        write!(f, "{{{repr}}}")
+    }
+}
+
+/// A component of a name template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplatePart {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// A template describing how to construct each new file name.
+///
+/// E.g. `img_{rand:8}_{stem}.{ext}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    parts: Vec<TemplatePart>,
+}
+impl FromStr for NameTemplate {
+    type Err = ParseNameTemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ParseNameTemplateError as E;
+
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('{', Some('{')) | ('}', Some('}')) => {
+                    chars.next();
+                    literal.push(c);
+                }
+                ('{', _) => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => Err(E::UnclosedPlaceholder)?,
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(TemplatePart::Placeholder(placeholder.parse()?));
+                }
+                ('}', _) => Err(E::UnmatchedBrace)?,
+                (c, _) => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+
+        let template = Self { parts };
+        if !template.placeholders().any(|p| matches!(p, Placeholder::Random(_))) {
+            Err(E::NoRandom)?;
+        }
+        Ok(template)
+    }
+}
+impl fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repr = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(s) => s.replace('{', "{{").replace('}', "}}"),
+                TemplatePart::Placeholder(p) => p.to_string(),
+            })
+            .join("");
+        write!(f, "{repr}")
+    }
+}
+impl NameTemplate {
+    pub fn parts(&self) -> &[TemplatePart] {
+        &self.parts
+    }
+
+    pub fn placeholders(&self) -> impl Iterator<Item = &Placeholder> {
+        self.parts.iter().filter_map(|part| match part {
+            TemplatePart::Placeholder(p) => Some(p),
+            TemplatePart::Literal(_) => None,
+        })
+    }
+
+    /// The total number of random characters required by this template,
+    /// using `default_length` for `{rand}` placeholders without an explicit length.
+    pub fn random_length(&self, default_length: usize) -> usize {
+        self.placeholders()
+            .filter_map(|p| match p {
+                Placeholder::Random(len) => Some(len.unwrap_or(default_length)),
+                _ => None,
+            })
+            .sum()
+    }
+}
@@ -1,14 +1,57 @@
-use std::{fmt, str::FromStr};
+use std::{
+    ffi::{OsStr, OsString},
+    fmt, fs, io,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
+use clap::crate_name;
 use dialoguer::Input;
+use itertools::Itertools;
+use log::debug;
 use yansi::Paint;
 
 use crate::cli::ExtensionModeSelection;
 
+/// Whether messages for the user go to stderr instead of stdout,
+/// because stdout carries machine-readable output.
+static MESSAGES_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Send all further messages for the user to stderr.
+pub fn redirect_messages_to_stderr() {
+    MESSAGES_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+pub fn messages_to_stderr() -> bool {
+    MESSAGES_TO_STDERR.load(Ordering::Relaxed)
+}
+
+/// Like `println!`, but prints to stderr once `redirect_messages_to_stderr` is called.
+macro_rules! message {
+    ($($arg:tt)*) => {
+        if $crate::util::messages_to_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use message;
+
+/// Extensions made of several parts that belong together, without the leading dot.
+///
+/// More can be added in `compound-extensions.txt` in the config directory.
+static COMPOUND_EXTENSIONS: [&str; 14] = [
+    "tar.gz", "tar.bz2", "tar.xz", "tar.zst", "tar.lz", "tar.lzma", "tar.Z", "user.js", "user.css", "d.ts", "d.mts",
+    "d.cts", "min.js", "min.css",
+];
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ExtensionMode {
     KeepAll,
     KeepLast,
+    /// Keep the longest matching compound extension from the list, falling back to `KeepLast`.
+    KnownCompound(Vec<String>),
     Static(String),
     Discard,
 }
@@ -22,6 +65,7 @@ impl TryFrom<(ExtensionModeSelection, Option<String>)> for ExtensionMode {
         Ok(match value {
             (S::KeepAll, _) => Self::KeepAll,
             (S::KeepLast, _) => Self::KeepLast,
+            (S::KnownCompound, _) => Self::KnownCompound(compound_extensions()?),
             (S::Static, Some(ext)) => Self::Static(ext),
             (S::Static, None) => Err("`--static-ext` should be required by clap".to_string())?,
             (S::Discard, _) => Self::Discard,
@@ -33,6 +77,7 @@ impl fmt::Display for ExtensionMode {
         let repr = match self {
             Self::KeepAll => "KeepAll".into(),
             Self::KeepLast => "KeepLast".into(),
+            Self::KnownCompound(exts) => format!("KnownCompound({})", exts.join(", ")),
             Self::Static(ext) => format!("Static(\"{ext}\")"),
             Self::Discard => "Discard".into(),
         };
@@ -40,6 +85,110 @@ impl fmt::Display for ExtensionMode {
     }
 }
 
+/// Get the built-in compound extensions, plus any listed by the user in the config directory.
+fn compound_extensions() -> Result<Vec<String>, String> {
+    let mut exts = COMPOUND_EXTENSIONS.map(str::to_owned).to_vec();
+    let Some(path) = dirs::config_dir().map(|dir| dir.join(crate_name!()).join("compound-extensions.txt")) else {
+        return Ok(exts);
+    };
+    match fs::read_to_string(&path) {
+        Ok(list) => {
+            debug!("Reading extra compound extensions from {path:?}.");
+            exts.extend(
+                parse_compound_extensions(&list)
+                    .map_err(|err| format!("Invalid compound extension list {path:?}: {err}"))?,
+            );
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => Err(format!("Failed to read compound extensions from {path:?}: {err}"))?,
+    }
+    Ok(exts)
+}
+
+/// Parse a list of compound extensions with one extension per line, with or without the leading dot.
+/// Empty lines and lines starting with `#` are ignored.
+fn parse_compound_extensions(list: &str) -> Result<Vec<String>, String> {
+    let exts = list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.strip_prefix('.').unwrap_or(line).to_owned())
+        .collect_vec();
+    let illegal_exts = exts
+        .iter()
+        .filter(|ext| !ext.contains('.') || ext.split('.').any(|part| part.is_empty() || !is_safe_name_part(part)))
+        .map(|ext| format!("\"{ext}\""))
+        .join(", ");
+    if !illegal_exts.is_empty() {
+        Err(format!("these are not compound extensions: {illegal_exts}"))?;
+    }
+    Ok(exts)
+}
+
+/// Sanitise a complete name, removing anything that is not filename-safe on this platform.
+///
+/// Over-long names are not truncated, so that the extension or something is never cut off silently.
+pub fn sanitise_name(name: &str) -> String {
+    use sanitize_filename as sf;
+    sf::sanitize_with_options(
+        name,
+        sf::Options {
+            truncate: false,
+            ..Default::default()
+        },
+    )
+}
+
+/// Sanitise a complete name that is not valid UTF-8, keeping its invalid bytes as they are.
+///
+/// The rest of the name is sanitised the same way as `sanitise_name`.
+#[cfg(unix)]
+pub fn sanitise_os_name(name: &OsStr) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+
+    // stand in for each invalid run with U+FFFD, remembering its bytes
+    let mut lossy = String::new();
+    let mut invalid_runs = vec![];
+    for chunk in name.as_encoded_bytes().utf8_chunks() {
+        lossy.push_str(chunk.valid());
+        invalid_runs.extend(chunk.valid().matches(char::REPLACEMENT_CHARACTER).map(|_| None));
+        if !chunk.invalid().is_empty() {
+            lossy.push(char::REPLACEMENT_CHARACTER);
+            invalid_runs.push(Some(chunk.invalid()));
+        }
+    }
+
+    let sanitised = sanitise_name(&lossy);
+    if sanitised.matches(char::REPLACEMENT_CHARACTER).count() != invalid_runs.len() {
+        // the stand-ins no longer line up, so give up on restoring the original bytes
+        return sanitised.into();
+    }
+    let mut invalid_runs = invalid_runs.into_iter();
+    let mut bytes = vec![];
+    for (idx, part) in sanitised.split(char::REPLACEMENT_CHARACTER).enumerate() {
+        if idx > 0 {
+            match invalid_runs.next().flatten() {
+                Some(run) => bytes.extend_from_slice(run),
+                None => bytes.extend_from_slice(char::REPLACEMENT_CHARACTER.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        bytes.extend_from_slice(part.as_bytes());
+    }
+    OsString::from_vec(bytes)
+}
+/// Sanitise a complete name that is not valid UTF-8, replacing its invalid parts.
+#[cfg(not(unix))]
+pub fn sanitise_os_name(name: &OsStr) -> OsString {
+    sanitise_name(&name.to_string_lossy()).into()
+}
+
+/// Check if `part` is filename-safe on this platform when used anywhere within a name.
+pub fn is_safe_name_part(part: &str) -> bool {
+    // pad the part, so that rules about the ends of names (e.g. trailing dots) do not apply
+    let padded = format!("_{part}_");
+    sanitise_name(&padded) == padded
+}
+
 /// Legal responses from the user when we encounter an error.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum OnErrorResponse {
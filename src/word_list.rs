@@ -0,0 +1,112 @@
+use std::{collections::HashMap, error::Error, fmt, ops::Index, str::FromStr};
+
+use itertools::Itertools;
+
+use crate::util::is_safe_name_part;
+
+/// The word list used when `--wordlist` is not specified.
+///
+/// This is the English word list of BIP-0039, which is dedicated to the public domain.
+/// Every word is lowercase and uniquely identified by its first 4 letters.
+static BIP39_ENGLISH: &str = include_str!("word_list/bip39_english.txt");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseWordListError {
+    Empty,
+    IllegalWords(Vec<String>),
+    DuplicateWords(Vec<String>),
+}
+impl Error for ParseWordListError {}
+impl fmt::Display for ParseWordListError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn words_to_string(words: &[String]) -> String {
+            words.iter().map(|w| format!("\"{w}\"")).join(", ")
+        }
+
+        let repr = match self {
+            Self::Empty => "the word list contains no words".into(),
+            Self::IllegalWords(words) => {
+                let words = words_to_string(words);
+                format!("the word list contains illegal words: {words}")
+            }
+            Self::DuplicateWords(words) => {
+                let words = words_to_string(words);
+                format!("the word list contains duplicate words: {words}")
+            }
+        };
+        write!(f, "{repr}")
+    }
+}
+impl From<ParseWordListError> for String {
+    fn from(err: ParseWordListError) -> Self {
+        err.to_string()
+    }
+}
+
+/// A list of words to build names from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WordList {
+    words: Vec<String>,
+}
+impl FromStr for WordList {
+    type Err = ParseWordListError;
+
+    /// Parse a word list with one word per line. Empty lines are ignored.
+    ///
+    /// Diceware-style lists (e.g. `11111\tabacus`) are also accepted,
+    /// in which case only the last whitespace-separated field of each line is used.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ParseWordListError as E;
+
+        let words = s
+            .lines()
+            .filter_map(|line| line.split_whitespace().last())
+            .map(str::to_owned)
+            .collect_vec();
+        if words.is_empty() {
+            Err(E::Empty)?;
+        }
+
+        let illegal_words = words.iter().filter(|w| !is_safe_name_part(w)).cloned().collect_vec();
+        if !illegal_words.is_empty() {
+            Err(E::IllegalWords(illegal_words))?;
+        }
+
+        let duplicate_words = words
+            .iter()
+            .fold(HashMap::<&str, usize>::new(), |mut map, w| {
+                *map.entry(w).or_default() += 1;
+                map
+            })
+            .into_iter()
+            .filter(|&(_, count)| count > 1)
+            .map(|(w, _)| w.to_owned())
+            .sorted()
+            .collect_vec();
+        if !duplicate_words.is_empty() {
+            Err(E::DuplicateWords(duplicate_words))?;
+        }
+
+        Ok(Self { words })
+    }
+}
+impl Index<usize> for WordList {
+    type Output = str;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.words[index]
+    }
+}
+impl WordList {
+    /// The word list bundled with this program.
+    pub fn bundled() -> Self {
+        BIP39_ENGLISH.parse().expect("the bundled word list should be valid")
+    }
+
+    pub fn words(&self) -> &[String] {
+        &self.words
+    }
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+}